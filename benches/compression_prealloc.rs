@@ -0,0 +1,90 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+// Compares LokiBuilder::compression_prealloc_percent settings by the number of reallocations and
+// the time it takes to compress typical batches. Run with `cargo bench --bench compression_prealloc`.
+
+use log::Log;
+use log_loki::{CompressionPolicy, LokiBuilder};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use url::Url;
+
+// Counts the reallocations of every thread, including the background thread of the logger
+struct CountingAlloc;
+
+static REALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        REALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const BATCHES: usize = 200;
+
+fn main() {
+    for lines in [100, 1000] {
+        for percent in [0, 25, 50, 100] {
+            // dry runs compress every batch without sending it anywhere
+            let labels = HashMap::from([(String::from("app"), String::from("bench"))]);
+            let loki = LokiBuilder::new(
+                Url::parse("http://127.0.0.1:3100/loki/api/v1/push").unwrap(),
+                labels,
+            )
+            .dry_run(true)
+            .max_logs(lines)
+            .compression(CompressionPolicy::Always)
+            .compression_prealloc_percent(percent)
+            .build();
+
+            // the lines are rendered up front, so that formatting them doesn't add reallocations
+            let handle = loki.handle();
+            let rendered: Vec<String> = (0..lines)
+                .map(|i| {
+                    format!(
+                        "level=info message=\"request handled\" path=/api/items/{} status=200",
+                        i
+                    )
+                })
+                .collect();
+
+            let reallocs = REALLOCS.load(Ordering::Relaxed);
+            let started = Instant::now();
+            for batch in 0..BATCHES {
+                for (i, line) in rendered.iter().enumerate() {
+                    handle.log_raw((batch * lines + i) as u128, line.clone());
+                }
+                loki.flush();
+            }
+            let elapsed = started.elapsed();
+
+            println!(
+                "{:>5} lines/batch, {:>3}% preallocated: {:>8} reallocs, {:>8.1?} per batch",
+                lines,
+                percent,
+                REALLOCS.load(Ordering::Relaxed) - reallocs,
+                elapsed / BATCHES as u32
+            );
+        }
+    }
+}
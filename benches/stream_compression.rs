@@ -0,0 +1,93 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+// Compares compressing the streams of a multi-route workload together in one push against
+// compressing each stream as a push of its own, which is what the logger does since every batch
+// holds a single stream. Run with `cargo bench --bench stream_compression`.
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde_json::{json, Value};
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+const LINES: usize = 1000;
+const ROUNDS: u32 = 50;
+
+// One stream of structured JSON lines, one of free-form text, and one of logfmt lines
+fn streams() -> Vec<Value> {
+    let json_lines = (0..LINES).map(|i| {
+        json!({
+            "ts": format!("{}", 1_700_000_000_000_000_000u128 + i as u128),
+            "line": json!({"level": "info", "user": i % 37, "latency_ms": i * 7 % 250}).to_string(),
+        })
+    });
+    let text_lines = (0..LINES).map(|i| {
+        json!({
+            "ts": format!("{}", 1_700_000_000_000_000_000u128 + i as u128),
+            "line": format!("worker {} finished job {:x} after {} retries", i % 8, i * 2654435761, i % 3),
+        })
+    });
+    let logfmt_lines = (0..LINES).map(|i| {
+        json!({
+            "ts": format!("{}", 1_700_000_000_000_000_000u128 + i as u128),
+            "line": format!("level=error message=\"request failed\" path=/api/items/{} status=503", i),
+        })
+    });
+
+    [
+        ("api", json_lines.collect::<Vec<_>>()),
+        ("worker", text_lines.collect()),
+        ("edge", logfmt_lines.collect()),
+    ]
+    .into_iter()
+    .map(|(app, values)| {
+        let values: Vec<Value> = values.iter().map(|v| json!([v["ts"], v["line"]])).collect();
+        json!({"stream": {"app": app}, "values": values})
+    })
+    .collect()
+}
+
+fn gzip(body: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body).unwrap();
+    encoder.finish().unwrap()
+}
+
+// Compresses the pushes ROUNDS times, returning the uncompressed and compressed sizes per round
+// and the time it took per round
+fn measure(pushes: &[Vec<u8>]) -> (usize, usize, Duration) {
+    let mut compressed = 0;
+    let started = Instant::now();
+    for _ in 0..ROUNDS {
+        compressed = pushes.iter().map(|p| gzip(p).len()).sum();
+    }
+    let uncompressed = pushes.iter().map(|p| p.len()).sum();
+    (uncompressed, compressed, started.elapsed() / ROUNDS)
+}
+
+fn main() {
+    let streams = streams();
+    let together = vec![serde_json::to_vec(&json!({ "streams": streams })).unwrap()];
+    let separate: Vec<Vec<u8>> = streams
+        .iter()
+        .map(|s| serde_json::to_vec(&json!({ "streams": [s] })).unwrap())
+        .collect();
+
+    for (name, pushes) in [("whole push", &together), ("per stream", &separate)] {
+        let (uncompressed, compressed, elapsed) = measure(pushes);
+        println!(
+            "{:>10}: {:>8} bytes -> {:>7} bytes ({:.1}%), {:>8.1?} per round",
+            name,
+            uncompressed,
+            compressed,
+            compressed as f64 * 100.0 / uncompressed as f64,
+            elapsed
+        );
+    }
+}
@@ -0,0 +1,110 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+use crate::{FailurePolicy, LokiBuilder};
+use log::LevelFilter;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use url::Url;
+
+// The contents of a config file. See `LokiBuilder::from_config_file`.
+#[derive(Deserialize)]
+struct LokiConfig {
+    endpoint: String,
+    labels: HashMap<String, String>,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    level: Option<String>,
+    max_logs: Option<usize>,
+    max_log_lifetime_secs: Option<u64>,
+    failure_policy: Option<FailurePolicyConfig>,
+    // anything else, which is rejected or warned about depending on strictness
+    #[serde(flatten)]
+    unknown: HashMap<String, serde_json::Value>,
+}
+
+// "drop", {"retry": 6}, or {"retry_until_secs": 3600}
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum FailurePolicyConfig {
+    Drop,
+    Retry(usize),
+    RetryUntilSecs(u64),
+}
+
+impl LokiBuilder {
+    /// Construct a new Loki builder from a JSON config file, so the endpoint and labels can be
+    /// managed without code changes. The file is an object with the keys `endpoint` and
+    /// `labels`, and optionally `headers`, `level`, `max_logs`, `max_log_lifetime_secs`, and
+    /// `failure_policy` (`"drop"`, `{"retry": 6}`, or `{"retry_until_secs": 3600}`). Unknown keys
+    /// are an error if `strict` is set, and are otherwise reported as a warning once the logger is
+    /// built. The builder can be configured further as usual.
+    pub fn from_config_file(path: &Path, strict: bool) -> io::Result<LokiBuilder> {
+        let invalid = |msg: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), msg),
+            )
+        };
+
+        let config: LokiConfig =
+            serde_json::from_str(&read_to_string(path)?).map_err(|e| invalid(e.to_string()))?;
+
+        let mut unknown: Vec<&String> = config.unknown.keys().collect();
+        unknown.sort();
+        if strict && !unknown.is_empty() {
+            return Err(invalid(format!("unknown keys {:?}", unknown)));
+        }
+
+        let endpoint = Url::parse(&config.endpoint)
+            .map_err(|e| invalid(format!("invalid endpoint {:?}: {}", config.endpoint, e)))?;
+        if config.labels.is_empty() {
+            return Err(invalid(String::from(
+                "at least one label must be specified",
+            )));
+        }
+
+        let mut b = LokiBuilder::new(endpoint, config.labels);
+        for key in unknown {
+            b.warnings.push(format!(
+                "Ignoring unknown key {:?} in {}",
+                key,
+                path.display()
+            ));
+        }
+        for (name, value) in &config.headers {
+            b = b.add_header(name, value);
+        }
+        if let Some(level) = config.level {
+            let level = level
+                .parse::<LevelFilter>()
+                .map_err(|_| invalid(format!("invalid level {:?}", level)))?;
+            b = b.level(level);
+        }
+        if let Some(lines) = config.max_logs {
+            b = b.max_logs(lines);
+        }
+        if let Some(secs) = config.max_log_lifetime_secs {
+            b = b.max_log_lifetime(Duration::from_secs(secs));
+        }
+        if let Some(policy) = config.failure_policy {
+            b = b.failure_policy(match policy {
+                FailurePolicyConfig::Drop => FailurePolicy::Drop,
+                FailurePolicyConfig::Retry(attempts) => FailurePolicy::Retry(attempts),
+                FailurePolicyConfig::RetryUntilSecs(secs) => {
+                    FailurePolicy::RetryUntil(Duration::from_secs(secs))
+                }
+            });
+        }
+        Ok(b)
+    }
+}
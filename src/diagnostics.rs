@@ -0,0 +1,42 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+use std::fmt::{Arguments, Debug, Formatter};
+
+/// `Diagnostics` specifies where the logger's own warnings go, such as failed pushes, dropped
+/// batches, or invalid configuration. See `LokiBuilder::diagnostics`.
+pub enum Diagnostics {
+    /// Warnings are printed to stderr, prefixed with `(Loki)`. This is the default.
+    Stderr,
+    /// Warnings are discarded
+    Silent,
+    /// Warnings are passed to the function, without the prefix. It may be called from any thread,
+    /// including the background thread, so it must not log through this logger.
+    Callback(Box<dyn Fn(&str) + Send + Sync>),
+}
+
+impl Debug for Diagnostics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Diagnostics::Stderr => f.write_str("Stderr"),
+            Diagnostics::Silent => f.write_str("Silent"),
+            Diagnostics::Callback(_) => f.write_str("Callback(..)"),
+        }
+    }
+}
+
+impl Diagnostics {
+    // Report a warning
+    pub(crate) fn report(&self, msg: Arguments) {
+        match self {
+            Diagnostics::Stderr => eprintln!("(Loki) {}", msg),
+            Diagnostics::Silent => {}
+            Diagnostics::Callback(f) => f(&msg.to_string()),
+        }
+    }
+}
@@ -0,0 +1,35 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+use std::fmt::{Display, Formatter};
+
+/// `LokiError` describes why a push to Loki failed.
+#[derive(PartialEq, Debug, Clone, Eq)]
+pub enum LokiError {
+    /// Loki (or a proxy in front of it) answered with an error status. The message contains the
+    /// status text and the start of the response body, which usually explains the rejection.
+    Status(u16, String),
+    /// The push couldn't be sent, e.g. because the connection failed or timed out
+    Transport(String),
+    /// The push couldn't be serialized or compressed
+    Encoding(String),
+    /// The background thread isn't running
+    Disconnected,
+}
+
+impl Display for LokiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LokiError::Status(code, message) => write!(f, "HTTP {}: {}", code, message),
+            LokiError::Transport(message) | LokiError::Encoding(message) => f.write_str(message),
+            LokiError::Disconnected => f.write_str("The log_loki thread is not running"),
+        }
+    }
+}
+
+impl std::error::Error for LokiError {}
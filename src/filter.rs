@@ -0,0 +1,76 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+use core::cmp::Reverse;
+use log::{LevelFilter, Metadata};
+
+// Directives holds per-module level rules parsed from a RUST_LOG style string
+#[derive(Default, Debug, Clone)]
+pub struct Directives {
+    default: Option<LevelFilter>,
+    // (module, level), sorted by descending module length so that the most specific rule wins
+    rules: Vec<(String, LevelFilter)>,
+}
+
+impl Directives {
+    // Parses `module=level,module2=level2,level`. A bare level sets the default, and a bare module
+    // enables every level for that module. Invalid directives are added to `warnings` and ignored.
+    pub fn parse(spec: &str, warnings: &mut Vec<String>) -> Directives {
+        let mut d = Directives::default();
+
+        for directive in spec.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => match level.trim().parse() {
+                    Ok(level) => d.rules.push((module.trim().to_owned(), level)),
+                    Err(_) => warnings.push(format!(
+                        "Ignoring filter directive {:?}: invalid level {:?}",
+                        directive, level
+                    )),
+                },
+                None => match directive.parse() {
+                    Ok(level) => d.default = Some(level),
+                    Err(_) => d.rules.push((directive.to_owned(), LevelFilter::Trace)),
+                },
+            }
+        }
+
+        d.rules.sort_by_key(|r| Reverse(r.0.len()));
+        d
+    }
+
+    // Returns whether a record with the given metadata passes the directives
+    pub fn enabled(&self, metadata: &Metadata) -> bool {
+        let target = metadata.target();
+        let level = self
+            .rules
+            .iter()
+            .find(|(module, _)| {
+                target == module
+                    || (target.starts_with(module.as_str())
+                        && target[module.len()..].starts_with("::"))
+            })
+            .map(|(_, level)| *level)
+            .or(self.default)
+            .unwrap_or(LevelFilter::Trace);
+
+        metadata.level() <= level
+    }
+
+    // Returns the most verbose level any directive allows
+    pub fn max_level(&self) -> LevelFilter {
+        if self.default.is_none() {
+            return LevelFilter::Trace;
+        }
+        self.rules
+            .iter()
+            .map(|(_, level)| *level)
+            .chain(self.default)
+            .max()
+            .unwrap_or(LevelFilter::Trace)
+    }
+}
@@ -13,4 +13,25 @@ use log::Record;
 /// logfmt `LokiFormatter` implementation, which is used by default.
 pub trait LokiFormatter: Send + Sync {
     fn write_record(&self, dst: &mut String, rec: &Record) -> std::fmt::Result;
+
+    /// Formats the record like `write_record`, but may also decide that the record should be
+    /// dropped instead of being sent to Loki. The default implementation defers to `write_record`
+    /// and never drops a record.
+    fn format_record(
+        &self,
+        dst: &mut String,
+        rec: &Record,
+    ) -> Result<FormatAction, std::fmt::Error> {
+        self.write_record(dst, rec).map(|()| FormatAction::Send)
+    }
+}
+
+/// `FormatAction` is returned by `LokiFormatter::format_record` to tell the logger what to do with
+/// the formatted record.
+#[derive(PartialEq, Debug, Clone, Copy, Eq)]
+pub enum FormatAction {
+    /// The formatted line is sent to Loki
+    Send,
+    /// The record is dropped and nothing is sent to Loki
+    Drop,
 }
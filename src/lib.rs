@@ -6,61 +6,340 @@ License, v. 2.0. If a copy of the MPL was not distributed with this
 file, You can obtain one at http://mozilla.org/MPL/2.0/.
 */
 
-use kanal::{unbounded, Sender};
-use log::{set_boxed_logger, set_max_level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use core::cmp::Reverse;
+use kanal::{bounded, unbounded, SendError, Sender};
+use log::{
+    logger, set_boxed_logger, set_max_level, Level, LevelFilter, Log, Metadata, Record,
+    SetLoggerError,
+};
 #[cfg(feature = "tls")]
 use rustls::client::ClientConfig;
-use std::collections::HashMap;
-use std::sync::{Arc, Condvar, Mutex};
-use std::thread::spawn;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, BufRead, BufReader};
+use std::marker::PhantomData;
+use std::panic::{catch_unwind, set_hook, take_hook};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock, PoisonError};
+use std::task::{Context, Poll};
+use std::thread::{current, sleep, Builder};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+pub use ureq::Resolver;
 use url::Url;
 
+// name of the background thread that sends logs to loki
+const TASK_THREAD_NAME: &str = "log_loki";
+// name of the thread that flushes the logger during a panic, see flush_on_panic
+const PANIC_FLUSH_THREAD_NAME: &str = "log_loki_panic_flush";
+// timeout of each HTTP request to loki
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// identifies each logger, for the micro batches of LokiBuilder::micro_batch
+static NEXT_LOGGER_ID: AtomicU64 = AtomicU64::new(1);
+
+// when the first logger of this process was built
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+// Time since the first logger of this process was built
+#[cfg(feature = "logfmt")]
+pub(crate) fn uptime() -> Duration {
+    STARTED.get_or_init(Instant::now).elapsed()
+}
+
 // background task for sending logs to loki
 mod task;
-use task::{LokiTask, LokiTaskMsg};
+use task::{
+    FlushSignal, LogEntry, LokiTask, LokiTaskMsg, MemoryBudget, MicroBatch, MicroBatches,
+    ScopeLabels, StructuredMetadata, TaskStatus,
+};
+// Errors reported by the logger
+mod error;
+pub use error::LokiError;
+// Where the logger's own warnings go
+mod diagnostics;
+pub use diagnostics::Diagnostics;
 // Write logs in LogFmt style by default
 mod fmt;
-pub use fmt::LokiFormatter;
+pub use fmt::{FormatAction, LokiFormatter};
+// Mock Loki server for integration tests
+#[cfg(feature = "test-util")]
+pub mod test_util;
+// Inspect and modify records before they are formatted
+mod middleware;
+pub use middleware::{LogMiddleware, MiddlewareAction};
+// RUST_LOG style per-module filtering
+mod filter;
+use filter::Directives;
+// Macros that need to expand in the calling crate
+mod macros;
+// Decide when failed batches are retried
+mod retry;
+pub use retry::{ExponentialBackoff, RetryScheduler};
+// Write logs as OpenTelemetry-style JSON objects
+mod otel;
+pub use otel::OtelFormatter;
+// Restrict the characters of formatted lines
+mod sanitize;
+pub use sanitize::SanitizeFormatter;
+// Build a LokiBuilder from a config file
+#[cfg(feature = "config")]
+mod config;
 #[cfg(feature = "logfmt")]
 mod logfmt;
+// Accept only a pinned server certificate
+#[cfg(feature = "tls")]
+mod pin;
 #[cfg(feature = "logfmt")]
-pub use logfmt::{LogfmtFormatter, LogfmtAutoFields};
+pub use logfmt::{ControlCharPolicy, LogfmtAutoFields, LogfmtCollisionPolicy, LogfmtFormatter};
+
+/// Returns the optional features of this crate that were compiled in, such as `compress` or
+/// `tls`, so that support tooling can report the actual build configuration.
+pub fn enabled_features() -> &'static [&'static str] {
+    &[
+        #[cfg(feature = "tls")]
+        "tls",
+        #[cfg(feature = "tls-native-certs")]
+        "tls-native-certs",
+        #[cfg(feature = "compress")]
+        "compress",
+        #[cfg(feature = "kv_unstable")]
+        "kv_unstable",
+        #[cfg(feature = "logfmt")]
+        "logfmt",
+        #[cfg(feature = "config")]
+        "config",
+        #[cfg(feature = "test-util")]
+        "test-util",
+    ]
+}
+
+/// `HeaderProvider` supplies fresh headers for each request. See `LokiBuilder::header_provider`.
+pub type HeaderProvider = Box<dyn Fn() -> HashMap<String, String> + Send + Sync>;
+
+/// `PushHook` is called after every push attempt. See `LokiBuilder::on_push`.
+pub type PushHook = Box<dyn Fn(&PushInfo) + Send + Sync>;
+
+/// `BatchResultHook` is called once the fate of a batch is final. See
+/// `LokiBuilder::on_batch_result`.
+pub type BatchResultHook = Box<dyn Fn(u64, Result<(), &str>) + Send + Sync>;
+
+/// `BacklogHook` is called when the logger becomes backlogged or recovers. See
+/// `LokiBuilder::on_backlog_change`.
+pub type BacklogHook = Box<dyn Fn(bool) + Send + Sync>;
+
+// A label whose value is resolved when the logger is built. See `LokiBuilder::label_from`.
+type LabelSource = (String, Box<dyn FnOnce() -> Option<String> + Send>);
+
+/// `PushInfo` describes a single attempt to push a batch to Loki.
+#[derive(PartialEq, Debug, Clone, Eq)]
+pub struct PushInfo {
+    /// The number of log lines in the batch
+    pub lines: usize,
+    /// The size of the serialized JSON payload in bytes
+    pub uncompressed_bytes: usize,
+    /// The size of the request body in bytes. Equal to `uncompressed_bytes` if the batch wasn't
+    /// compressed.
+    pub compressed_bytes: usize,
+    /// How long it took to serialize and compress the batch
+    pub encode_duration: Duration,
+    /// How long it took to send the request and receive the response status, excluding the time
+    /// the batch spent waiting. Zero for dry runs.
+    pub duration: Duration,
+    /// Whether Loki accepted the batch
+    pub success: bool,
+}
+
+/// `LokiConfigSummary` is a snapshot of the configuration a `Loki` logger was built with. See
+/// `Loki::config_summary`.
+#[derive(PartialEq, Debug, Clone, Eq)]
+pub struct LokiConfigSummary {
+    /// The endpoint logs are pushed to, unless they are routed elsewhere by level
+    pub endpoint: Url,
+    /// The optional features of this crate that were compiled in. See `enabled_features`.
+    pub enabled_features: &'static [&'static str],
+    /// The names of the labels attached to every stream, sorted
+    pub label_keys: Vec<String>,
+    /// The most verbose level that is logged
+    pub level_filter: LevelFilter,
+    /// The maximum number of lines in a batch, as currently in effect
+    pub max_log_lines: usize,
+    /// The maximum time a line waits in a batch before the batch is sent, as currently in effect
+    pub max_log_lifetime: Duration,
+    /// When batches are compressed
+    #[cfg(feature = "compress")]
+    pub compression: CompressionPolicy,
+    /// How batches are compressed
+    #[cfg(feature = "compress")]
+    pub compression_codec: CompressionCodec,
+    /// How failed batches are handled
+    pub failure_policy: FailurePolicy,
+}
 
 /// `LokiBuilder` is used to construct the `Loki` object.
 #[must_use = "Has no affect unless .build() is called."]
 pub struct LokiBuilder {
     endpoint: Url,
     labels: HashMap<String, String>,
+    // label names in the order they were added, for preserve_label_order
+    label_order: Vec<String>,
+    preserve_label_order: bool,
+    label_sources: Vec<LabelSource>,
+    max_values_per_label: HashMap<String, usize>,
+    retention_label: Option<(String, HashMap<Level, String>)>,
+    reserved_label_policy: ReservedLabelPolicy,
     headers: HashMap<String, String>,
+    content_type: Option<String>,
+    header_provider: Option<HeaderProvider>,
+    on_push: Option<PushHook>,
+    on_batch_result: Option<BatchResultHook>,
+    on_backlog_change: Option<BacklogHook>,
+    backlog_thresholds: Option<(usize, usize)>,
     #[cfg(feature = "tls")]
     tls_config: Option<Arc<ClientConfig>>,
     max_log_lines: usize,
+    initial_batch_capacity: Option<usize>,
+    memory_budget: Option<usize>,
     max_log_lifetime: Duration,
     failure_policy: FailurePolicy,
+    retry_scheduler: Box<dyn RetryScheduler>,
+    retry_order: RetryOrder,
+    max_sample_age: Option<(Duration, StaleSamplePolicy)>,
+    clock_skew_guard: Option<(Duration, ClockSkewPolicy)>,
+    retry_spacing: Option<Duration>,
+    retry_time_budget: Option<Duration>,
+    adaptive_batch_sizing: Option<(Duration, usize, usize)>,
+    flush_timeout: Option<Duration>,
+    fallback_file: Option<PathBuf>,
+    fallback_after: Duration,
+    fallback_max_batches: usize,
+    value_shape: ValueShape,
     level_filter: LevelFilter,
+    directives: Directives,
+    middleware: Vec<Box<dyn LogMiddleware>>,
     formatter: Option<Box<dyn LokiFormatter>>,
+    target_formatters: Vec<(String, Box<dyn LokiFormatter>)>,
+    resolver: Option<Box<dyn Resolver>>,
+    shutdown_summary: bool,
+    shutdown_phase_label: bool,
+    report_drops_to_loki: bool,
+    dry_run: bool,
+    stream_shards: Option<u32>,
+    unhealthy_after: usize,
+    circuit_breaker: Option<(usize, Duration)>,
+    skip_empty_lines: bool,
+    split_multiline: bool,
+    never_panic: bool,
+    micro_batch: Option<(usize, Duration)>,
+    diagnostics: Arc<Diagnostics>,
+    // warnings about the configuration, reported once the logger is built
+    warnings: Vec<String>,
+    #[cfg(feature = "kv_unstable")]
+    capture_kv: bool,
+    #[cfg(feature = "kv_unstable")]
+    metadata_keys: Vec<String>,
+    source_location_as_metadata: bool,
+    max_idle_connections: Option<usize>,
+    max_idle_connections_per_host: Option<usize>,
+    follow_redirects: bool,
+    level_routes: HashMap<Level, Url>,
+    level_remaps: Vec<(Level, Level, Option<String>)>,
+    #[cfg(feature = "compress")]
+    compression: CompressionPolicy,
+    #[cfg(feature = "compress")]
+    compression_codec: CompressionCodec,
+    #[cfg(feature = "compress")]
+    compression_prealloc_percent: usize,
+    #[cfg(feature = "compress")]
+    compress_dlq: bool,
 }
 
 impl LokiBuilder {
-    /// Construct a new Loki builder with the given endpoint and labels.
+    /// Construct a new Loki builder with the given endpoint and labels. IPv6 hosts are written in
+    /// brackets, as in `http://[::1]:3100/loki/api/v1/push`.
     pub fn new(endpoint: Url, labels: HashMap<String, String>) -> LokiBuilder {
         assert!(!labels.is_empty(), "At least one label must be specified!");
+        let mut label_order: Vec<String> = labels.keys().cloned().collect();
+        label_order.sort();
 
         LokiBuilder {
             endpoint,
             labels,
+            label_order,
+            preserve_label_order: false,
+            label_sources: Vec::new(),
+            max_values_per_label: HashMap::new(),
+            retention_label: None,
+            reserved_label_policy: ReservedLabelPolicy::Warn,
             headers: HashMap::new(),
+            content_type: None,
+            header_provider: None,
+            on_push: None,
+            on_batch_result: None,
+            on_backlog_change: None,
+            backlog_thresholds: None,
             #[cfg(feature = "tls")]
             tls_config: None, // if unset, uses default
             max_log_lines: 4096,
+            initial_batch_capacity: None,
+            memory_budget: None,
             max_log_lifetime: Duration::from_secs(300),
             failure_policy: FailurePolicy::Retry(6),
+            retry_scheduler: Box::new(ExponentialBackoff::default()),
+            retry_order: RetryOrder::OldestFirst,
+            max_sample_age: None,
+            clock_skew_guard: None,
+            retry_spacing: None,
+            retry_time_budget: None,
+            adaptive_batch_sizing: None,
+            flush_timeout: None,
+            fallback_file: None,
+            fallback_after: Duration::from_secs(300),
+            fallback_max_batches: 64,
+            value_shape: ValueShape::TimestampLine,
             level_filter: LevelFilter::Trace,
+            directives: Directives::default(),
+            middleware: Vec::new(),
             #[cfg(feature = "logfmt")]
             formatter: Some(Box::new(LogfmtFormatter::default())),
             #[cfg(not(feature = "logfmt"))]
             formatter: None,
+            target_formatters: Vec::new(),
+            resolver: None,
+            shutdown_summary: false,
+            shutdown_phase_label: false,
+            report_drops_to_loki: false,
+            dry_run: false,
+            stream_shards: None,
+            unhealthy_after: 3,
+            circuit_breaker: None,
+            skip_empty_lines: false,
+            split_multiline: false,
+            never_panic: false,
+            micro_batch: None,
+            diagnostics: Arc::new(Diagnostics::Stderr),
+            warnings: Vec::new(),
+            #[cfg(feature = "kv_unstable")]
+            capture_kv: false,
+            #[cfg(feature = "kv_unstable")]
+            metadata_keys: Vec::new(),
+            source_location_as_metadata: false,
+            max_idle_connections: None,
+            max_idle_connections_per_host: None,
+            follow_redirects: false,
+            level_routes: HashMap::new(),
+            level_remaps: Vec::new(),
+            #[cfg(feature = "compress")]
+            compression: CompressionPolicy::Always,
+            #[cfg(feature = "compress")]
+            compression_codec: CompressionCodec::Gzip,
+            #[cfg(feature = "compress")]
+            compression_prealloc_percent: 50,
+            #[cfg(feature = "compress")]
+            compress_dlq: false,
         }
     }
 
@@ -70,6 +349,62 @@ impl LokiBuilder {
         self
     }
 
+    /// Specify the Content-Type header of pushes, for Loki-compatible endpoints that expect
+    /// something other than the default of `application/json; charset=utf-8`. This only changes
+    /// the header, not the body.
+    pub fn content_type(mut self, content_type: &str) -> LokiBuilder {
+        self.content_type = Some(content_type.to_owned());
+        self
+    }
+
+    /// Specify a function that supplies headers for each HTTP(s) request to Loki, such as a
+    /// regularly rotated bearer token. It is called on the background thread right before every
+    /// push, and the headers it returns replace any static headers with the same name.
+    pub fn header_provider(mut self, provider: HeaderProvider) -> LokiBuilder {
+        self.header_provider = Some(provider);
+        self
+    }
+
+    /// Specify a function that is called on the background thread after every push attempt,
+    /// including retries and dry runs, with the size of the batch before and after compression and
+    /// the time the request took. Useful for tuning the batch size, checking the compression
+    /// ratio, and watching push latency, which tends to rise before Loki starts failing.
+    pub fn on_push(mut self, hook: PushHook) -> LokiBuilder {
+        self.on_push = Some(hook);
+        self
+    }
+
+    /// Specify a function that is called on the background thread with the id of a batch once it
+    /// has been delivered (`Ok`) or will never be delivered (`Err` with the reason), e.g. because
+    /// it ran out of retries, was written to the fallback file, or was drained. Each batch is
+    /// assigned an increasing id when its first line is added, which is kept across retries.
+    pub fn on_batch_result(mut self, hook: BatchResultHook) -> LokiBuilder {
+        self.on_batch_result = Some(hook);
+        self
+    }
+
+    /// Specify a function that is called on the background thread with `true` when the logger
+    /// becomes backlogged, and with `false` once it has recovered. The backlog is the number of
+    /// lines waiting in the channel to the background thread plus the lines of batches waiting
+    /// to be retried. See `LokiBuilder::backlog_thresholds` for when it counts as backlogged.
+    pub fn on_backlog_change(mut self, hook: BacklogHook) -> LokiBuilder {
+        self.on_backlog_change = Some(hook);
+        self
+    }
+
+    /// Specifies the backlog (in lines) at which the logger counts as backlogged, and the backlog
+    /// it has to fall to before it counts as recovered. The gap between the two keeps the
+    /// `on_backlog_change` hook from flapping. The default is four batches and one batch, as set
+    /// by `LokiBuilder::max_logs`.
+    pub fn backlog_thresholds(mut self, high: usize, low: usize) -> LokiBuilder {
+        assert!(
+            low < high,
+            "The low threshold must be below the high threshold!"
+        );
+        self.backlog_thresholds = Some((high, low));
+        self
+    }
+
     #[cfg(feature = "tls")]
     /// Configure rustls for HTTPS requests. Passed directly to ureq.
     pub fn tls_config(mut self, tls_config: Arc<ClientConfig>) -> LokiBuilder {
@@ -77,6 +412,21 @@ impl LokiBuilder {
         self
     }
 
+    #[cfg(feature = "tls")]
+    /// Configure rustls to accept only the server certificate whose DER encoding has the given
+    /// SHA-256 fingerprint. Any other certificate fails the connection, even if it is signed by a
+    /// trusted CA, and the push fails with a certificate error. Since the pinned certificate is
+    /// trusted as is, its CA chain, host name, and expiry aren't checked. This replaces any
+    /// earlier `tls_config`.
+    pub fn pin_cert_sha256(mut self, fingerprint: &[u8; 32]) -> LokiBuilder {
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(pin::PinnedCertVerifier::new(*fingerprint)))
+            .with_no_client_auth();
+        self.tls_config = Some(Arc::new(config));
+        self
+    }
+
     /// Specifies the maximum number of log lines that may be written before
     /// the log batch must be sent to Loki
     pub fn max_logs(mut self, lines: usize) -> LokiBuilder {
@@ -84,6 +434,33 @@ impl LokiBuilder {
         self
     }
 
+    /// Adjusts the maximum number of log lines in a batch at runtime, so that serializing and
+    /// compressing a batch takes about `target`. When a batch takes longer than that, the limit is
+    /// halved, and when a full batch takes less than half of it, the limit grows by a quarter. The
+    /// limit starts at `max_logs` and always stays between `min` and `max` lines. This keeps the
+    /// work per push predictable on constrained devices, whatever the size of the lines.
+    pub fn adaptive_batch_sizing(
+        mut self,
+        target: Duration,
+        min: usize,
+        max: usize,
+    ) -> LokiBuilder {
+        assert!(
+            min > 0 && min <= max,
+            "The minimum batch size must be positive and no larger than the maximum!"
+        );
+        self.adaptive_batch_sizing = Some((target, min, max));
+        self
+    }
+
+    /// Specifies how many log lines the buffer for the batch of each endpoint preallocates space
+    /// for. Defaults to the value of `max_logs`. Lowering this saves memory when `max_logs` is large
+    /// but batches are usually small. Batches for label scopes start empty and grow as needed.
+    pub fn initial_batch_capacity(mut self, lines: usize) -> LokiBuilder {
+        self.initial_batch_capacity = Some(lines);
+        self
+    }
+
     /// Specifies the maximum number of seconds that log lines may resize in the buffer
     /// before they are sent to Loki
     pub fn max_log_lifetime(mut self, secs: Duration) -> LokiBuilder {
@@ -97,17 +474,490 @@ impl LokiBuilder {
         self
     }
 
+    /// Specifies when failed batches are retried, for batches that the failure policy allows to be
+    /// retried. The default is `ExponentialBackoff`.
+    pub fn retry_scheduler(mut self, scheduler: Box<dyn RetryScheduler>) -> LokiBuilder {
+        self.retry_scheduler = scheduler;
+        self
+    }
+
+    /// Specifies the age after which Loki rejects samples (`reject_old_samples_max_age` in Loki's
+    /// configuration). Batches whose logs are older than this when they are sent, typically
+    /// because they waited in the retry queue, are handled according to `policy` instead of being
+    /// retried over and over. By default, the age of samples is not checked.
+    pub fn max_sample_age(mut self, max_age: Duration, policy: StaleSamplePolicy) -> LokiBuilder {
+        self.max_sample_age = Some((max_age, policy));
+        self
+    }
+
+    /// Guards against the system clock jumping backwards, e.g. after an NTP correction. Records
+    /// whose timestamp is more than `tolerance` behind the latest timestamp this logger has seen
+    /// are handled according to `policy`, as Loki would likely reject them as out of order. By
+    /// default, timestamps are taken as they are.
+    pub fn clock_skew_guard(mut self, tolerance: Duration, policy: ClockSkewPolicy) -> LokiBuilder {
+        self.clock_skew_guard = Some((tolerance, policy));
+        self
+    }
+
+    /// Caps the memory held by logs that haven't been delivered yet, across the channel to the
+    /// background thread, the pending batches, and the retry queue. Each log line is estimated
+    /// at its length plus a small fixed overhead. Once the budget is exhausted, new logs are
+    /// dropped (and counted as dropped) until enough batches are delivered or dropped to make
+    /// room again. By default, there is no budget.
+    pub fn memory_budget(mut self, bytes: usize) -> LokiBuilder {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Specifies how long `flush()` waits for the flush to finish before giving up with a warning.
+    /// By default, this is derived from the timeout of each request to Loki (30 seconds) and the
+    /// failure policy: the request timeout times the number of attempts for `Retry`, the longer
+    /// of the request timeout and the retry time for `RetryUntil`, and the request timeout for
+    /// `Drop`.
+    pub fn flush_timeout(mut self, timeout: Duration) -> LokiBuilder {
+        self.flush_timeout = Some(timeout);
+        self
+    }
+
+    /// Specifies the minimum time between two retries of failed batches, so that a large retry
+    /// queue is drained gradually instead of all at once when Loki recovers from an outage.
+    /// Retries forced by a flush ignore the spacing. By default, there is no spacing.
+    pub fn retry_spacing(mut self, spacing: Duration) -> LokiBuilder {
+        self.retry_spacing = Some(spacing);
+        self
+    }
+
+    /// Specifies how long the background thread may spend retrying failed batches before it goes
+    /// back to batching new logs. The budget is checked between retries, so a single slow push
+    /// can still exceed it. Retries forced by a flush ignore the budget. By default, the thread
+    /// retries every batch that is due before it looks at new logs again.
+    pub fn retry_time_budget(mut self, budget: Duration) -> LokiBuilder {
+        self.retry_time_budget = Some(budget);
+        self
+    }
+
+    /// Specifies the order in which failed batches are retried. The default is to retry the
+    /// oldest batches first.
+    pub fn retry_order(mut self, order: RetryOrder) -> LokiBuilder {
+        self.retry_order = order;
+        self
+    }
+
+    /// Specifies a file that failed batches are appended to instead of being kept in memory for
+    /// retrying while Loki is down. Logs are written as JSON lines containing the `labels`, `ts`,
+    /// and `line` of each log, so they can be imported manually later. Batches go back to being
+    /// retried in memory once a push succeeds again. See `fallback_threshold` for when the
+    /// fallback file is used.
+    pub fn fallback_file(mut self, path: PathBuf) -> LokiBuilder {
+        self.fallback_file = Some(path);
+        self
+    }
+
+    /// Specifies when the fallback file starts being used: once pushes have been failing for
+    /// `outage`, or once `max_batches` failed batches are waiting to be retried, whichever comes
+    /// first. The default is 5 minutes or 64 batches. Has no effect unless `fallback_file` is set.
+    pub fn fallback_threshold(mut self, outage: Duration, max_batches: usize) -> LokiBuilder {
+        self.fallback_after = outage;
+        self.fallback_max_batches = max_batches;
+        self
+    }
+
+    /// Specifies how each log entry is laid out in the pushed JSON. Only needed for Loki-compatible
+    /// servers that don't accept Loki's own `[timestamp, line]` layout, which is the default.
+    pub fn value_shape(mut self, shape: ValueShape) -> LokiBuilder {
+        self.value_shape = shape;
+        self
+    }
+
     /// Sets the verbosity of this logger
     pub fn level(mut self, lf: LevelFilter) -> LokiBuilder {
         self.level_filter = lf;
         self
     }
 
+    /// Filters logs by module using `RUST_LOG` style directives, e.g. `info,my_crate::db=trace`.
+    /// A bare level sets the default, `module=level` sets the level of a module and its
+    /// submodules, and a bare module enables all of its logs. The most specific module wins.
+    /// These are applied in addition to `LokiBuilder::level`. Invalid directives are ignored.
+    pub fn filter_directives(mut self, directives: &str) -> LokiBuilder {
+        self.directives = Directives::parse(directives, &mut self.warnings);
+        self
+    }
+
+    /// Adds a middleware that inspects every enabled record before it is formatted. Middlewares
+    /// run in the order they are added.
+    pub fn middleware(mut self, middleware: Box<dyn LogMiddleware>) -> LokiBuilder {
+        self.middleware.push(middleware);
+        self
+    }
+
+    /// Sends logs of the given level to a different Loki endpoint. Levels without a route are sent
+    /// to the endpoint passed to `LokiBuilder::new`. Each endpoint is batched and retried separately.
+    pub fn route_level(mut self, level: Level, endpoint: Url) -> LokiBuilder {
+        self.level_routes.insert(level, endpoint);
+        self
+    }
+
+    #[cfg(feature = "compress")]
+    /// Specifies when batches should be compressed. The default is to always compress.
+    /// Compression is applied once to the whole serialized push. Each push carries a single
+    /// stream, since logs with different labels are batched separately, so streams are never
+    /// compressed together.
+    pub fn compression(mut self, policy: CompressionPolicy) -> LokiBuilder {
+        self.compression = policy;
+        self
+    }
+
+    #[cfg(feature = "compress")]
+    /// Specifies the codec batches are compressed with. The default is gzip.
+    pub fn compression_codec(mut self, codec: CompressionCodec) -> LokiBuilder {
+        self.compression_codec = codec;
+        self
+    }
+
+    #[cfg(feature = "compress")]
+    /// Picks the codec from a list of `Content-Encoding` names in order of preference, such as
+    /// the encodings advertised by a proxy in front of Loki. The first name this build supports
+    /// is used: `gzip` and `deflate` select the codec, and `identity` disables compression.
+    /// Unsupported names (e.g. `zstd`) are skipped. If none are supported, the codec is left as
+    /// it was.
+    pub fn preferred_encodings(mut self, encodings: &[&str]) -> LokiBuilder {
+        for encoding in encodings {
+            match encoding.trim().to_ascii_lowercase().as_str() {
+                "gzip" => self.compression_codec = CompressionCodec::Gzip,
+                "deflate" => self.compression_codec = CompressionCodec::Deflate,
+                "identity" => self.compression = CompressionPolicy::Never,
+                _ => continue,
+            }
+            return self;
+        }
+
+        self.warnings.push(format!(
+            "None of the preferred encodings {:?} are supported, keeping {:?}",
+            encodings, self.compression_codec
+        ));
+        self
+    }
+
+    #[cfg(feature = "compress")]
+    /// Specifies the size of the buffer that compressed batches are written to, as a percentage
+    /// of the uncompressed size. Setting this close to the typical compression ratio avoids
+    /// reallocating the buffer while compressing. The default is 50%, and at most 100% is allowed.
+    pub fn compression_prealloc_percent(mut self, percent: usize) -> LokiBuilder {
+        assert!(percent <= 100, "At most 100% may be preallocated!");
+        self.compression_prealloc_percent = percent;
+        self
+    }
+
+    #[cfg(feature = "compress")]
+    /// When enabled, the lines of batches waiting to be retried are kept compressed in memory and
+    /// only decompressed when the batch is retried. This trades CPU time for memory during long
+    /// outages. Disabled by default.
+    pub fn compress_dlq(mut self, enabled: bool) -> LokiBuilder {
+        self.compress_dlq = enabled;
+        self
+    }
+
+    /// Logs records of level `from` as if they were logged at level `to`, optionally only for
+    /// records whose target starts with `target_prefix`. The remapped level is used for filtering,
+    /// formatting, and routing. This is useful for taming third-party crates that log at the wrong
+    /// level. The first matching remap is applied.
+    pub fn remap_level(
+        mut self,
+        from: Level,
+        to: Level,
+        target_prefix: Option<&str>,
+    ) -> LokiBuilder {
+        self.level_remaps
+            .push((from, to, target_prefix.map(String::from)));
+        self
+    }
+
     pub fn formatter(mut self, fmt: Box<dyn LokiFormatter>) -> LokiBuilder {
         self.formatter = Some(fmt);
         self
     }
 
+    /// Formats records whose target starts with `target_prefix` with `fmt` instead of the default
+    /// formatter, e.g. to log one subsystem as JSON. If several prefixes match, the longest one
+    /// wins.
+    pub fn formatter_for_target(
+        mut self,
+        target_prefix: &str,
+        fmt: Box<dyn LokiFormatter>,
+    ) -> LokiBuilder {
+        self.target_formatters.push((target_prefix.to_owned(), fmt));
+        self
+    }
+
+    /// Use a custom resolver to look up the address of the Loki host instead of the system resolver.
+    /// Passed directly to ureq.
+    pub fn resolver(mut self, resolver: impl Resolver + 'static) -> LokiBuilder {
+        self.resolver = Some(Box::new(resolver));
+        self
+    }
+
+    /// Specifies the maximum number of idle connections kept in the connection pool. Connections
+    /// to Loki are reused across pushes while they are in the pool. Passed directly to ureq. With
+    /// the current ureq, idle connections can't be expired by age, only limited in number.
+    pub fn max_idle_connections(mut self, max: usize) -> LokiBuilder {
+        self.max_idle_connections = Some(max);
+        self
+    }
+
+    /// Specifies the maximum number of idle connections kept in the connection pool per host.
+    /// Passed directly to ureq. With the current ureq, idle connections can't be expired by age.
+    pub fn max_idle_connections_per_host(mut self, max: usize) -> LokiBuilder {
+        self.max_idle_connections_per_host = Some(max);
+        self
+    }
+
+    /// Specifies whether redirects returned by Loki are followed. By default they are not, and a
+    /// redirect fails the push with the redirect target in the error message, as redirecting a
+    /// push usually points to a misconfigured endpoint. Note that ureq turns a redirected POST into
+    /// a GET for 301, 302, and 303 responses, and doesn't follow 307 and 308 for POSTs at all, so
+    /// enabling this rarely results in the logs reaching Loki.
+    pub fn follow_redirects(mut self, enabled: bool) -> LokiBuilder {
+        self.follow_redirects = enabled;
+        self
+    }
+
+    /// When enabled, logs are formatted, batched, serialized, and compressed as usual, but batches
+    /// are never actually sent to Loki. Every batch is counted as delivered instead. Useful for
+    /// validating a configuration without polluting Loki.
+    pub fn dry_run(mut self, enabled: bool) -> LokiBuilder {
+        self.dry_run = enabled;
+        self
+    }
+
+    /// Spreads logs across `shards` streams by adding a `shard` label to each batch, computed from
+    /// the minute the batch was started modulo `shards`. This can help with very high-volume
+    /// logging, where Loki recommends against putting everything into one giant stream. Note that
+    /// this multiplies the number of streams (and thus the cardinality) by up to `shards`.
+    pub fn stream_sharding(mut self, shards: u32) -> LokiBuilder {
+        assert!(shards > 0, "At least one shard must be specified!");
+        self.stream_shards = Some(shards);
+        self
+    }
+
+    /// Sets the `service` label to the given name. See the `loki_builder!` macro for using the name
+    /// of the calling crate instead.
+    pub fn with_service_label(mut self, name: &str) -> LokiBuilder {
+        self.track_label("service");
+        self.labels.insert("service".to_owned(), name.to_owned());
+        self
+    }
+
+    /// Sets the label `key` to the given version, e.g. to correlate logs with releases. See the
+    /// `with_version_label!` macro for using the version of the calling crate.
+    pub fn version_label(mut self, key: &str, version: &str) -> LokiBuilder {
+        self.track_label(key);
+        self.labels.insert(key.to_owned(), version.to_owned());
+        self
+    }
+
+    /// Merges `labels` into the labels given so far, e.g. to layer labels from a config file over
+    /// static defaults. Later calls take precedence: a label that was already set, whether through
+    /// `LokiBuilder::new` or an earlier call to this or another builder method, is overridden by
+    /// the value in `labels`. Labels from `LokiBuilder::label_from` are resolved when the logger
+    /// is built, so they take precedence over merged labels.
+    pub fn merge_labels(mut self, labels: HashMap<String, String>) -> LokiBuilder {
+        let mut keys: Vec<&String> = labels.keys().collect();
+        keys.sort();
+        for key in keys {
+            self.track_label(key);
+        }
+        self.labels.extend(labels);
+        self
+    }
+
+    /// Sets the label `key` to the value returned by `f`, which is called once when the logger is
+    /// built, e.g. to read a pod name from a file. If it returns `None`, the label is left out (or
+    /// keeps the value it was given through `LokiBuilder::new`).
+    pub fn label_from(
+        mut self,
+        key: &str,
+        f: impl FnOnce() -> Option<String> + Send + 'static,
+    ) -> LokiBuilder {
+        self.track_label(key);
+        self.label_sources.push((key.to_owned(), Box::new(f)));
+        self
+    }
+
+    /// Caps the number of distinct values the label `key` may take through label scopes (see
+    /// `Loki::label_scope`) at `n`. Once `n` values have been seen, logs with new values are sent
+    /// with the value `other` instead of creating yet another stream. This keeps a single
+    /// unexpectedly high-cardinality label from overwhelming Loki, while keeping the first values.
+    pub fn max_values_per_label(mut self, key: &str, n: usize) -> LokiBuilder {
+        self.max_values_per_label.insert(key.to_owned(), n);
+        self
+    }
+
+    /// Sets the label `key` on each log line to the value given for its level in `values`, e.g.
+    /// `retention=long` for errors and `retention=short` for debug logs, so that Loki can apply a
+    /// different retention period to each stream. Levels without a value don't get the label.
+    /// Like the labels of label scopes, each value is sent in batches of its own, and a value set
+    /// by a label scope takes precedence.
+    pub fn retention_label(mut self, key: &str, values: HashMap<Level, String>) -> LokiBuilder {
+        self.track_label(key);
+        self.retention_label = Some((key.to_owned(), values));
+        self
+    }
+
+    /// Specifies what happens when the logger is built with a label whose name Loki reserves for
+    /// internal use, such as `__name__` or anything else starting with `__`. Loki rejects such
+    /// labels with errors that don't point at the cause. Labels added by label scopes aren't
+    /// checked. The default is to warn.
+    pub fn reserved_labels(mut self, policy: ReservedLabelPolicy) -> LokiBuilder {
+        self.reserved_label_policy = policy;
+        self
+    }
+
+    /// When enabled, the labels of each stream are serialized in the order they were added, which
+    /// keeps the output stable for golden-file tests. Since the labels passed to
+    /// `LokiBuilder::new` have no order, they come first, sorted by name, followed by the labels
+    /// added through builder methods in the order those were called. Labels from label scopes or
+    /// stream sharding come last, sorted by name. Disabled by default.
+    pub fn preserve_label_order(mut self, enabled: bool) -> LokiBuilder {
+        self.preserve_label_order = enabled;
+        self
+    }
+
+    // Remember the position of a label added after construction
+    fn track_label(&mut self, key: &str) {
+        if !self.label_order.iter().any(|k| k == key) {
+            self.label_order.push(key.to_owned());
+        }
+    }
+
+    /// When enabled, records that the formatter renders as an empty or whitespace-only line are not
+    /// sent to Loki.
+    pub fn skip_empty_lines(mut self, enabled: bool) -> LokiBuilder {
+        self.skip_empty_lines = enabled;
+        self
+    }
+
+    /// When enabled, records that the formatter renders as multiple lines (e.g. stack traces) are
+    /// sent to Loki as one entry per line. The timestamp of each line is 1ns after the previous
+    /// one to preserve their order. Note that this changes the number of entries sent.
+    pub fn split_multiline(mut self, enabled: bool) -> LokiBuilder {
+        self.split_multiline = enabled;
+        self
+    }
+
+    /// When enabled, `log()` and `flush()` degrade gracefully instead of panicking when their
+    /// assumptions break. Specifically:
+    /// - a system clock set before the Unix epoch drops the record instead of panicking
+    /// - a formatter returning an error drops the record (with a note sent to the diagnostics sink,
+    ///   stderr by default)
+    /// - a background thread that is no longer running drops the record, or makes `flush()`
+    ///   return immediately, and marks the logger as unhealthy (see `Loki::is_healthy`)
+    ///
+    /// Panics raised by user-provided formatters, middlewares, or hooks are not caught.
+    pub fn never_panic(mut self, enabled: bool) -> LokiBuilder {
+        self.never_panic = enabled;
+        self
+    }
+
+    /// Buffers the lines logged on each thread and hands them to the background thread in groups
+    /// of up to `lines`, which cuts the per-log overhead of tight logging loops. A thread's group
+    /// is handed over once it is full, and the background thread collects any group whose first
+    /// line is at least `max_delay` old, so the lines of a thread that stops logging still go out.
+    /// Flushing, draining, or dumping on any thread includes the lines buffered on every thread.
+    /// Disabled by default.
+    pub fn micro_batch(mut self, lines: usize, max_delay: Duration) -> LokiBuilder {
+        assert!(lines > 0, "At least one line must be allowed!");
+        self.micro_batch = Some((lines, max_delay));
+        self
+    }
+
+    /// Specifies where the logger's own warnings go, such as failed pushes and dropped batches. By
+    /// default, they are printed to stderr, which can loop back into the logs when stderr is
+    /// collected as well.
+    pub fn diagnostics(mut self, diagnostics: Diagnostics) -> LokiBuilder {
+        self.diagnostics = Arc::new(diagnostics);
+        self
+    }
+
+    #[cfg(feature = "kv_unstable")]
+    /// When enabled, the key value pairs of each record are captured on the logging thread and
+    /// sent along with its line as structured metadata, regardless of what the formatter does
+    /// with them. This needs Loki 2.9 or later with structured metadata enabled. If the formatter
+    /// also renders the pairs, they end up in both places. Disabled by default, since copying the
+    /// pairs has a cost.
+    pub fn capture_kv(mut self, enabled: bool) -> LokiBuilder {
+        self.capture_kv = enabled;
+        self
+    }
+
+    #[cfg(feature = "kv_unstable")]
+    /// Sends the key value pairs with the given keys, such as `request_id`, as structured metadata
+    /// instead of passing them to the formatter, so that high-cardinality identifiers are kept
+    /// with each line without becoming labels or part of the line. Other pairs are left to the
+    /// formatter. This needs Loki 2.9 or later with structured metadata enabled.
+    pub fn metadata_keys(mut self, keys: &[&str]) -> LokiBuilder {
+        self.metadata_keys = keys.iter().map(|&k| k.to_owned()).collect();
+        self
+    }
+
+    /// When enabled, the `file` and `line` of each record are sent along with its line as the
+    /// structured metadata keys `file` and `line`, when the record has them. This makes them
+    /// filterable in Loki without parsing the line, so they can be left out of the formatted line.
+    /// This needs Loki 2.9 or later with structured metadata enabled. Disabled by default.
+    pub fn source_location_as_metadata(mut self, enabled: bool) -> LokiBuilder {
+        self.source_location_as_metadata = enabled;
+        self
+    }
+
+    /// Specifies how many consecutive pushes must fail before `Loki::is_healthy` reports the
+    /// logger as unhealthy. The default is 3.
+    pub fn unhealthy_after(mut self, failures: usize) -> LokiBuilder {
+        self.unhealthy_after = failures;
+        self
+    }
+
+    /// Stops sending to Loki for `cooldown` after `failures` consecutive pushes failed, instead of
+    /// spending time on pushes that are bound to fail during an outage. In the meantime, batches
+    /// are kept for later without using up their retry attempts (within the memory budget, if
+    /// any), and flushes don't send anything either. Once the cooldown is over, the next push
+    /// probes Loki: if it succeeds, sending resumes as usual, and otherwise the breaker stays open
+    /// for another cooldown. Changes of the breaker's state are reported as warnings. Disabled by
+    /// default.
+    pub fn circuit_breaker(mut self, failures: usize, cooldown: Duration) -> LokiBuilder {
+        assert!(failures > 0, "At least one failure must be allowed!");
+        self.circuit_breaker = Some((failures, cooldown));
+        self
+    }
+
+    /// When enabled, a final line summarizing the number of lines delivered, dropped, and still
+    /// waiting to be retried, as well as the uptime of the logger, is formatted with the default formatter and sent to Loki by
+    /// `Loki::shutdown`, or once the logger and all of its handles are dropped, whichever comes
+    /// first.
+    pub fn shutdown_summary(mut self, enabled: bool) -> LokiBuilder {
+        self.shutdown_summary = enabled;
+        self
+    }
+
+    /// When enabled, lines logged after `Loki::shutdown` was called get the extra label
+    /// `phase="shutdown"`, which sets the logs of a slow drain apart from normal operation.
+    /// Disabled by default.
+    pub fn shutdown_phase_label(mut self, enabled: bool) -> LokiBuilder {
+        self.shutdown_phase_label = enabled;
+        self
+    }
+
+    /// When enabled, a line recording the number of lines lost and why is sent to Loki whenever a
+    /// batch is dropped, so that data loss is visible in Grafana even if stderr isn't collected.
+    /// These lines go to a stream of their own, with the extra label
+    /// `log_loki_internal="dropped"`. They are sent once and given up on if that fails. While Loki
+    /// is unreachable or the circuit breaker is open, the drops are counted instead, and reported
+    /// in a single line after the next successful push, along with the lines rejected by
+    /// `LokiBuilder::memory_budget` in the meantime.
+    pub fn report_drops_to_loki(mut self, enabled: bool) -> LokiBuilder {
+        self.report_drops_to_loki = enabled;
+        self
+    }
+
     pub fn build(self) -> Loki {
         Loki::start(self)
     }
@@ -123,107 +973,975 @@ pub enum FailurePolicy {
     /// this only works if Loki accepts out of order writes.
     /// See: <https://grafana.com/docs/loki/latest/configuration/#accept-out-of-order-writes>
     Retry(usize),
+    /// Log batches that fail to send are retried on the same exponential backoff curve until
+    /// the specified amount of time has passed since the first failure, regardless of how many
+    /// attempts that takes. This fits Loki's ingestion window better than an attempt count, as Loki
+    /// rejects samples that are too old anyway.
+    RetryUntil(Duration),
+}
+
+/// `RetryOrder` specifies which failed batch is retried next when several are waiting.
+#[derive(PartialEq, Debug, Clone, Copy, Eq)]
+pub enum RetryOrder {
+    /// Batches are retried in the order their retries were scheduled in
+    OldestFirst,
+    /// Of the batches that are due for a retry, the one containing the most recent logs is retried
+    /// first. After a long outage, this delivers the most operationally relevant logs first and
+    /// lets older ones trickle in.
+    NewestFirst,
+}
+
+/// `StaleSamplePolicy` specifies what happens to batches older than `LokiBuilder::max_sample_age`.
+#[derive(PartialEq, Debug, Clone, Copy, Eq)]
+pub enum StaleSamplePolicy {
+    /// The batch is dropped
+    Drop,
+    /// The timestamps of the batch are rewritten to the current time, preserving the order of the
+    /// logs but not their original time
+    Rewrite,
+}
+
+/// `ClockSkewPolicy` specifies what happens to records that are too far behind the latest
+/// timestamp. See `LokiBuilder::clock_skew_guard`.
+#[derive(PartialEq, Debug, Clone, Copy, Eq)]
+pub enum ClockSkewPolicy {
+    /// The record is dropped
+    Drop,
+    /// The record is logged with the latest timestamp instead
+    Clamp,
+}
+
+/// `ReservedLabelPolicy` specifies what happens to labels with names Loki reserves. See
+/// `LokiBuilder::reserved_labels`.
+#[derive(PartialEq, Debug, Clone, Copy, Eq)]
+pub enum ReservedLabelPolicy {
+    /// A warning is reported when the logger is built
+    Warn,
+    /// Building the logger panics
+    Reject,
+}
+
+/// `ValueShape` specifies how each log entry in a stream's `values` is serialized.
+#[derive(PartialEq, Debug, Clone, Copy, Eq)]
+pub enum ValueShape {
+    /// `["<timestamp>", "<line>"]`, as expected by Loki
+    TimestampLine,
+    /// `["<line>", "<timestamp>"]`
+    LineTimestamp,
+    /// `{"ts": "<timestamp>", "line": "<line>"}`
+    Object,
+}
+
+#[cfg(feature = "compress")]
+/// `CompressionPolicy` specifies when log batches are compressed before they are sent to Loki.
+/// The `Content-Encoding` header is only sent with batches that were compressed.
+#[derive(PartialEq, Debug, Clone, Copy, Eq)]
+pub enum CompressionPolicy {
+    /// Every batch is compressed
+    Always,
+    /// Batches are never compressed
+    Never,
+    /// Batches are compressed only if their serialized size exceeds the given number of bytes.
+    /// Compressing small batches often costs more CPU time than it saves in bandwidth.
+    AboveBytes(usize),
+}
+
+#[cfg(feature = "compress")]
+/// `CompressionCodec` specifies how log batches are compressed, along with the `Content-Encoding`
+/// they are sent with.
+#[derive(PartialEq, Debug, Clone, Copy, Eq)]
+pub enum CompressionCodec {
+    /// Compress with gzip (`Content-Encoding: gzip`)
+    Gzip,
+    /// Compress with zlib (`Content-Encoding: deflate`), for proxies that don't accept gzip
+    Deflate,
 }
 
 /// Logger implementation that writes its logs to Loki. Create one using the `LokiBuilder`.
 pub struct Loki {
     tx: Sender<LokiTaskMsg>,
+    id: u64,
+    micro_batch: Option<(usize, Duration)>,
+    memory_budget: Option<Arc<MemoryBudget>>,
+    micro_batches: MicroBatches,
     level_filter: LevelFilter,
-    flush_notif: Arc<(Mutex<bool>, Condvar)>,
-    fmt: Box<dyn LokiFormatter>,
+    directives: Directives,
+    middleware: Vec<Box<dyn LogMiddleware>>,
+    flush_notif: Arc<(Mutex<(u64, u64)>, Condvar)>,
+    flush_timeout: Duration,
+    config: LokiConfigSummary,
+    status: Arc<TaskStatus>,
+    skip_empty_lines: bool,
+    split_multiline: bool,
+    never_panic: bool,
+    diagnostics: Arc<Diagnostics>,
+    #[cfg(feature = "kv_unstable")]
+    capture_kv: bool,
+    #[cfg(feature = "kv_unstable")]
+    metadata_keys: Vec<String>,
+    source_location_as_metadata: bool,
+    clock_skew_guard: Option<(Duration, ClockSkewPolicy)>,
+    // the latest timestamp seen by the clock skew guard, and whether skew was reported yet
+    latest_ts: AtomicU64,
+    skew_reported: AtomicBool,
+    level_remaps: Vec<(Level, Level, Option<String>)>,
+    fmt: Arc<dyn LokiFormatter>,
+    // (target prefix, formatter), sorted by descending prefix length
+    target_formatters: Vec<(String, Box<dyn LokiFormatter>)>,
 }
 
 impl Loki {
-    fn start(b: LokiBuilder) -> Loki {
+    fn start(mut b: LokiBuilder) -> Loki {
+        STARTED.get_or_init(Instant::now);
+        for (key, f) in std::mem::take(&mut b.label_sources) {
+            if let Some(value) = f() {
+                b.labels.insert(key, value);
+            }
+        }
+        let mut label_keys: Vec<String> = b.labels.keys().cloned().collect();
+        label_keys.sort();
+        let config = LokiConfigSummary {
+            endpoint: b.endpoint.clone(),
+            enabled_features: enabled_features(),
+            label_keys,
+            level_filter: b.level_filter,
+            max_log_lines: b.max_log_lines,
+            max_log_lifetime: b.max_log_lifetime,
+            #[cfg(feature = "compress")]
+            compression: b.compression,
+            #[cfg(feature = "compress")]
+            compression_codec: b.compression_codec,
+            failure_policy: b.failure_policy.clone(),
+        };
         let filter = b.level_filter;
+        let directives = std::mem::take(&mut b.directives);
+        let middleware = std::mem::take(&mut b.middleware);
+        let skip_empty_lines = b.skip_empty_lines;
+        let split_multiline = b.split_multiline;
+        let never_panic = b.never_panic;
+        let micro_batch = b.micro_batch;
+        let diagnostics = Arc::clone(&b.diagnostics);
+        for warning in std::mem::take(&mut b.warnings) {
+            diagnostics.report(format_args!("{}", warning));
+        }
+        let retention_key = b.retention_label.as_ref().map(|(key, _)| key);
+        for key in config.label_keys.iter().chain(retention_key) {
+            if !key.starts_with("__") {
+                continue;
+            }
+            match b.reserved_label_policy {
+                ReservedLabelPolicy::Warn => diagnostics.report(format_args!(
+                    "The label {:?} is reserved by Loki, pushes will likely be rejected",
+                    key
+                )),
+                ReservedLabelPolicy::Reject => panic!("The label {:?} is reserved by Loki!", key),
+            }
+        }
+        #[cfg(feature = "kv_unstable")]
+        let capture_kv = b.capture_kv;
+        #[cfg(feature = "kv_unstable")]
+        let metadata_keys = std::mem::take(&mut b.metadata_keys);
+        let source_location_as_metadata = b.source_location_as_metadata;
+        let clock_skew_guard = b.clock_skew_guard;
+        let level_remaps = std::mem::take(&mut b.level_remaps);
         let (tx, rx) = unbounded::<LokiTaskMsg>();
-        let flush_notif = Arc::new((Mutex::new(false), Condvar::new()));
+        let flush_notif = Arc::new((Mutex::new((0, 0)), Condvar::new()));
+        let flush_timeout = b.flush_timeout.unwrap_or(match b.failure_policy {
+            FailurePolicy::Retry(max_retries) => REQUEST_TIMEOUT.saturating_mul(
+                u32::try_from(max_retries)
+                    .unwrap_or(u32::MAX)
+                    .saturating_add(1),
+            ),
+            FailurePolicy::RetryUntil(max_age) => max_age.max(REQUEST_TIMEOUT),
+            FailurePolicy::Drop => REQUEST_TIMEOUT,
+        });
         let flush_notif2 = Arc::clone(&flush_notif);
-        let fmt = b.formatter;
+        let status = Arc::new(TaskStatus::new(
+            match b.adaptive_batch_sizing {
+                Some((_, min, max)) => b.max_log_lines.clamp(min, max),
+                None => b.max_log_lines,
+            },
+            b.max_log_lifetime,
+        ));
+        let status2 = Arc::clone(&status);
+        let memory_budget = b
+            .memory_budget
+            .map(|limit| Arc::new(MemoryBudget::new(limit, Arc::clone(&diagnostics))));
+        let memory_budget2 = memory_budget.clone();
+        let micro_batches = MicroBatches::default();
+        let micro_batches2 = Arc::clone(&micro_batches);
+        let fmt: Arc<dyn LokiFormatter> = Arc::from(b.formatter.take().expect(
+            "When the logfmt feature is disabled, you are required to provide a formatter.",
+        ));
+        let fmt2 = Arc::clone(&fmt);
+        let mut target_formatters = std::mem::take(&mut b.target_formatters);
+        target_formatters.sort_by_key(|f| Reverse(f.0.len()));
 
-        spawn(move || {
-            #[cfg(feature = "tls")]
-            LokiTask::new(
-                rx,
-                flush_notif2,
-                b.endpoint,
-                b.headers,
-                b.labels,
-                b.max_log_lines,
-                b.max_log_lifetime,
-                b.failure_policy,
-                b.tls_config,
-            )
-            .run();
-            #[cfg(not(feature = "tls"))]
-            LokiTask::new(
-                rx,
-                flush_notif2,
-                b.endpoint,
-                b.headers,
-                b.labels,
-                b.max_log_lines,
-                b.max_log_lifetime,
-                b.failure_policy,
-            )
-            .run();
-        });
+        Builder::new()
+            .name(TASK_THREAD_NAME.to_owned())
+            .spawn(move || {
+                LokiTask::new(
+                    rx,
+                    flush_notif2,
+                    status2,
+                    memory_budget2,
+                    micro_batches2,
+                    fmt2,
+                    b,
+                )
+                .run();
+            })
+            .expect("Failed to spawn the log_loki thread.");
 
         Loki {
             tx,
+            id: NEXT_LOGGER_ID.fetch_add(1, Ordering::Relaxed),
+            micro_batch,
+            memory_budget,
+            micro_batches,
             level_filter: filter,
+            directives,
+            middleware,
             flush_notif,
-            fmt: fmt.expect(
-                "When the logfmt feature is disabled, you are required to provide a formatter.",
-            ),
+            flush_timeout,
+            config,
+            status,
+            skip_empty_lines,
+            split_multiline,
+            never_panic,
+            diagnostics,
+            #[cfg(feature = "kv_unstable")]
+            capture_kv,
+            #[cfg(feature = "kv_unstable")]
+            metadata_keys,
+            source_location_as_metadata,
+            clock_skew_guard,
+            latest_ts: AtomicU64::new(0),
+            skew_reported: AtomicBool::new(false),
+            level_remaps,
+            fmt,
+            target_formatters,
         }
     }
 
-    /// Installs the logger as the default logger for the entire program.
-    /// Calling this (or any similar function from other libraries) more than once is a bug.
-    pub fn apply(self) -> Result<(), SetLoggerError> {
-        set_max_level(self.level_filter);
-        set_boxed_logger(Box::from(self))
+    /// Adds labels to every log emitted on the current thread until the returned guard is dropped,
+    /// e.g. for the duration of a request. Scopes can be nested, and inner scopes take precedence
+    /// over outer ones and over the labels passed to `LokiBuilder::new`. Logs with scope labels
+    /// are batched separately from other logs, as they belong to a different stream. Applies to
+    /// any installed `Loki` logger, so this can be used after `apply()`.
+    pub fn label_scope(labels: HashMap<String, String>) -> LabelScopeGuard {
+        LABEL_SCOPES.with(|scopes| scopes.borrow_mut().push(labels));
+        LabelScopeGuard {
+            _not_send: PhantomData,
+        }
     }
-}
 
-impl Log for Loki {
-    fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= self.level_filter
+    /// Returns the configuration this logger was built with, e.g. for debugging missing logs or
+    /// for an admin endpoint. The batch limits are the ones currently in effect, after changes at
+    /// runtime by `LokiHandle` or `LokiBuilder::adaptive_batch_sizing`.
+    pub fn config_summary(&self) -> LokiConfigSummary {
+        LokiConfigSummary {
+            max_log_lines: self.status.max_log_lines.load(Ordering::Relaxed),
+            max_log_lifetime: self.status.max_log_lifetime(),
+            ..self.config.clone()
+        }
     }
 
-    fn log(&self, record: &Record) {
+    /// Returns a handle that can still reach this logger after it has been installed with
+    /// `apply()`. Note that the background thread keeps running until both the logger and all of
+    /// its handles are dropped.
+    pub fn handle(&self) -> LokiHandle {
+        LokiHandle {
+            tx: self.tx.clone(),
+            memory_budget: self.memory_budget.clone(),
+        }
+    }
+
+    /// Queues pre-timestamped lines (in nanoseconds since the Unix epoch), e.g. to backfill an
+    /// archive of old logs, and returns once all of them are queued. The lines are batched and
+    /// retried like other logs, but are kept in batches of their own so that they don't mix with
+    /// live logs. While the background thread is more than a batch behind, this waits for it to
+    /// catch up instead of buffering the whole iterator in memory.
+    ///
+    /// A few things to keep in mind, since the timestamps are old:
+    /// - the lines should be sorted by timestamp, as timestamps within a batch are bumped to be
+    ///   strictly increasing
+    /// - `LokiBuilder::max_sample_age` applies to these batches too, so it should be left unset
+    ///   (or set to Loki's own limit with `StaleSamplePolicy::Drop`) to avoid rewriting their
+    ///   timestamps
+    /// - Loki has to accept samples this old (`reject_old_samples_max_age`) and out of order
+    ///   writes for streams that already have newer logs
+    pub fn ingest_bulk(&self, lines: impl Iterator<Item = (u128, String)>) {
+        // an empty label scope gets batches of its own, without adding any labels
+        let scope = Some(ScopeLabels::new());
+
+        for (ts, line) in lines {
+            while self.tx.len() >= self.status.max_log_lines.load(Ordering::Relaxed)
+                && !self.tx.is_disconnected()
+            {
+                sleep(Duration::from_millis(10));
+            }
+            self.send_line(ts, Level::Info, line, scope.clone(), None);
+        }
+    }
+
+    /// Returns false if the last pushes to Loki have failed more times in a row than allowed by
+    /// `LokiBuilder::unhealthy_after`, and true otherwise. Suitable for readiness probes.
+    pub fn is_healthy(&self) -> bool {
+        self.status.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Like `flush()`, but returns a future that resolves once the flush is done instead of blocking
+    /// the calling thread. The flush is requested immediately, not when the future is first polled.
+    /// The future doesn't depend on any particular async runtime.
+    pub fn flush_async(&self) -> impl Future<Output = ()> {
+        let signal = Arc::new(FlushSignal::default());
+
+        self.tx
+            .send(LokiTaskMsg::FlushAsync(Arc::clone(&signal)))
+            .expect("The other thread should be running");
+
+        FlushFuture(signal)
+    }
+
+    /// Asks the background thread to flush without waiting for the flush to finish, e.g. for
+    /// periodic flushes from a timer thread. Use `flush()` when the logs must have been pushed
+    /// before continuing, like during shutdown.
+    pub fn request_flush(&self) {
+        self.tx
+            .send(LokiTaskMsg::RequestFlush)
+            .expect("The other thread should be running");
+    }
+
+    /// Submits the pending batches like `Loki::request_flush`, then blocks until everything logged
+    /// so far has been delivered to Loki (or dropped after failing), including batches that are
+    /// waiting to be retried and lines buffered on other threads by `LokiBuilder::micro_batch`, and
+    /// nothing else is waiting to be sent. Unlike `flush()`, this waits for retries too, which makes
+    /// it useful for test teardown or at the end of batch jobs. Returns whether that point was
+    /// reached before `timeout`, e.g. false if Loki is unreachable or other threads keep logging.
+    pub fn wait_quiescent(&self, timeout: Duration) -> bool {
+        let (tx, rx) = bounded(1);
+        if self.tx.send(LokiTaskMsg::Quiesce(tx)).is_err() {
+            return false;
+        }
+        // a timeout too large to represent means waiting for as long as it takes
+        match Instant::now().checked_add(timeout) {
+            Some(_) => rx.recv_timeout(timeout).is_ok(),
+            None => rx.recv().is_ok(),
+        }
+    }
+
+    /// Flushes all pending logs, then sends the summary of `LokiBuilder::shutdown_summary` if it is
+    /// enabled, and blocks until both are done. Meant to be called right before the program exits,
+    /// as a logger installed with `apply()` is never dropped. Returns false if this took longer than
+    /// `timeout`. The logger keeps working afterwards, but no further summary is sent, and later
+    /// lines are labeled if `LokiBuilder::shutdown_phase_label` is enabled.
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        self.handle().shutdown(timeout)
+    }
+
+    /// Removes all logs that have not yet been delivered to Loki from the logger and returns them
+    /// as `(timestamp, line)` pairs sorted by timestamp. This includes the batches that are waiting
+    /// to be retried. The returned logs will never be sent by the logger, so this can be used to
+    /// hand them to a different sink if Loki is down for good.
+    pub fn drain(&self) -> Vec<(u128, String)> {
+        let (tx, rx) = bounded(1);
+
+        self.tx
+            .send(LokiTaskMsg::Drain(tx))
+            .expect("The other thread should be running");
+
+        rx.recv().expect("The other thread should reply")
+    }
+
+    /// Returns the lines in the batches that are waiting to be sent, joined with newlines and
+    /// ordered by timestamp. Unlike `Loki::drain`, the lines are left in place. Batches that are
+    /// waiting to be retried are not included. Meant for debugging.
+    pub fn dump_pending(&self) -> String {
+        let (tx, rx) = bounded(1);
+
+        self.tx
+            .send(LokiTaskMsg::Dump(tx))
+            .expect("The other thread should be running");
+
+        rx.recv().expect("The other thread should reply").join("\n")
+    }
+
+    /// Pushes a single test line to the default endpoint and waits for Loki's answer, returning
+    /// the error if the push failed, e.g. because of bad credentials or an unreachable endpoint.
+    /// The line goes through the same serialization, compression, and headers as regular pushes,
+    /// but it is sent right away and never retried. Meant for startup health checks, before
+    /// `apply()` is called. In dry run mode, nothing is sent and this always succeeds.
+    pub fn self_test(&self) -> Result<(), LokiError> {
+        let (tx, rx) = bounded(1);
+
+        self.tx
+            .send(LokiTaskMsg::SelfTest(tx))
+            .map_err(|_| LokiError::Disconnected)?;
+
+        rx.recv().map_err(|_| LokiError::Disconnected)?
+    }
+
+    /// Writes the batches that are waiting to be retried to `path`, e.g. before shutting down during
+    /// an outage, and returns the number of lines written. The exported batches are removed from
+    /// the retry queue, unless writing the file fails. The file uses the same JSON lines format as
+    /// `LokiBuilder::fallback_file`, and can be replayed with `Loki::import_dlq`. If `path` already
+    /// exists, nothing is written and an `AlreadyExists` error is returned.
+    pub fn export_dlq(&self, path: &Path) -> io::Result<usize> {
+        let (tx, rx) = bounded(1);
+
+        self.tx
+            .send(LokiTaskMsg::ExportDlq(path.to_owned(), tx))
+            .expect("The other thread should be running");
+
+        rx.recv().expect("The other thread should reply")
+    }
+
+    /// Queues the lines of a file written by `Loki::export_dlq` (or `LokiBuilder::fallback_file`)
+    /// for delivery with their original timestamps, labels, and structured metadata, and returns
+    /// the number of lines queued. The labels of this logger are added to the labels in the file,
+    /// but values from the file win. Nothing is queued if any line of the file is invalid. The
+    /// caveats of `Loki::ingest_bulk` about old timestamps apply here too.
+    pub fn import_dlq(&self, path: &Path) -> io::Result<usize> {
+        let invalid = |n: usize| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Line {} of {} is not a valid entry", n + 1, path.display()),
+            )
+        };
+        let strings = |v: &serde_json::Value| -> Option<Vec<(String, String)>> {
+            let mut pairs: Vec<(String, String)> = v
+                .as_object()?
+                .iter()
+                .map(|(k, v)| Some((k.clone(), v.as_str()?.to_owned())))
+                .collect::<Option<_>>()?;
+            pairs.sort();
+            Some(pairs)
+        };
+
+        let mut entries = Vec::new();
+        for (n, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: serde_json::Value = serde_json::from_str(&line).map_err(|_| invalid(n))?;
+            let labels = strings(&entry["labels"]).ok_or_else(|| invalid(n))?;
+            let ts = entry["ts"]
+                .as_str()
+                .and_then(|ts| ts.parse::<u128>().ok())
+                .ok_or_else(|| invalid(n))?;
+            let text = entry["line"].as_str().ok_or_else(|| invalid(n))?;
+            let metadata = match &entry["metadata"] {
+                serde_json::Value::Null => None,
+                m => Some(strings(m).ok_or_else(|| invalid(n))?),
+            };
+            entries.push((ts, text.to_owned(), labels, metadata));
+        }
+
+        let count = entries.len();
+        // the labels are applied like a label scope, which also keeps the lines in batches of
+        // their own
+        for (ts, line, labels, metadata) in entries {
+            self.send_line(ts, Level::Info, line, Some(labels), metadata);
+        }
+        Ok(count)
+    }
+
+    // Run the record through the middlewares and send it, unless it is filtered out.
+    fn send_record(&self, record: &Record) {
         if !self.enabled(record.metadata()) {
             return;
         }
 
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
+        #[cfg(feature = "kv_unstable")]
+        let mut fields: Vec<(String, String)> = Vec::new();
+
+        for m in &self.middleware {
+            #[cfg(feature = "kv_unstable")]
+            let action = if fields.is_empty() {
+                m.process(record)
+            } else {
+                m.process(&with_fields(record, &[record.key_values(), &fields]))
+            };
+            #[cfg(not(feature = "kv_unstable"))]
+            let action = m.process(record);
+
+            match action {
+                MiddlewareAction::Keep => {}
+                MiddlewareAction::Drop => return,
+                #[cfg(feature = "kv_unstable")]
+                MiddlewareAction::Modify(f) => fields.extend(f),
+            }
+        }
+
+        #[cfg(feature = "kv_unstable")]
+        if !fields.is_empty() {
+            self.format_and_send(&with_fields(record, &[record.key_values(), &fields]));
+            return;
+        }
+
+        self.format_and_send(record);
+    }
+
+    // Format the record and send it to the background thread.
+    fn format_and_send(&self, record: &Record) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH);
+        if self.never_panic && now.is_err() {
+            return;
+        }
+        let mut now = now
             .expect("The current moment is after the Unix Epoch.")
             .as_nanos();
 
+        if let Some((tolerance, policy)) = self.clock_skew_guard {
+            let latest = u128::from(self.latest_ts.fetch_max(now as u64, Ordering::Relaxed));
+            if now.saturating_add(tolerance.as_nanos()) < latest {
+                if !self.skew_reported.swap(true, Ordering::Relaxed) {
+                    self.diagnostics.report(format_args!(
+                        "The system clock went back by {:?}, applying {:?} to records until it catches up",
+                        Duration::from_nanos((latest - now) as u64),
+                        policy
+                    ));
+                }
+                match policy {
+                    ClockSkewPolicy::Drop => return,
+                    ClockSkewPolicy::Clamp => now = latest,
+                }
+            } else if now >= latest && self.skew_reported.load(Ordering::Relaxed) {
+                // the clock caught up, so the next jump back is reported again
+                self.skew_reported.store(false, Ordering::Relaxed);
+            }
+        }
+
+        // the formatter doesn't get to see the pairs that are sent as metadata
+        #[cfg(feature = "kv_unstable")]
+        let without_metadata = WithoutKeys(record.key_values(), &self.metadata_keys);
+        #[cfg(feature = "kv_unstable")]
+        let stripped;
+        #[cfg(feature = "kv_unstable")]
+        let fmt_record = if self.metadata_keys.is_empty() {
+            record
+        } else {
+            stripped = with_fields(record, &without_metadata);
+            &stripped
+        };
+        #[cfg(not(feature = "kv_unstable"))]
+        let fmt_record = record;
+
         let mut s = String::new();
-        self.fmt
-            .write_record(&mut s, record)
-            .expect("LokiFormatters shouldn't fail here.");
+        let fmt = self
+            .target_formatters
+            .iter()
+            .find(|(prefix, _)| record.target().starts_with(prefix.as_str()))
+            .map_or(&*self.fmt, |(_, fmt)| &**fmt);
+        let action = fmt.format_record(&mut s, fmt_record);
+        if self.never_panic && action.is_err() {
+            self.diagnostics
+                .report(format_args!("Failed to format a record, dropping it..."));
+            return;
+        }
+        let action = action.expect("LokiFormatters shouldn't fail here.");
+        if action == FormatAction::Drop || (self.skip_empty_lines && s.trim().is_empty()) {
+            return;
+        }
+
+        let scope = current_scope_labels();
+        let metadata = self.structured_metadata(record);
+
+        if !self.split_multiline {
+            self.send_line(now, record.level(), s, scope, metadata);
+            return;
+        }
+
+        let lines = s
+            .lines()
+            .filter(|l| !(self.skip_empty_lines && l.trim().is_empty()));
+        for (i, line) in lines.enumerate() {
+            self.send_line(
+                now + i as u128,
+                record.level(),
+                line.to_owned(),
+                scope.clone(),
+                metadata.clone(),
+            );
+        }
+    }
+
+    // Collect the parts of the record that are sent as structured metadata, if any
+    fn structured_metadata(&self, record: &Record) -> Option<StructuredMetadata> {
+        let mut pairs = StructuredMetadata::new();
+
+        #[cfg(feature = "kv_unstable")]
+        if self.capture_kv || !self.metadata_keys.is_empty() {
+            record
+                .key_values()
+                .visit(&mut KvCollector(&mut pairs))
+                .expect("This visitor should not return an error");
+            if !self.capture_kv {
+                pairs.retain(|(k, _)| self.metadata_keys.contains(k));
+            }
+        }
+
+        if self.source_location_as_metadata {
+            if let Some(file) = record.file() {
+                pairs.push((String::from("file"), file.to_owned()));
+            }
+            if let Some(line) = record.line() {
+                pairs.push((String::from("line"), line.to_string()));
+            }
+        }
+
+        (!pairs.is_empty()).then_some(pairs)
+    }
+
+    // Send a formatted line to the background thread, unless the memory budget is exhausted.
+    fn send_line(
+        &self,
+        ts: u128,
+        level: Level,
+        line: String,
+        scope: Option<ScopeLabels>,
+        metadata: Option<StructuredMetadata>,
+    ) {
+        if let Some(budget) = &self.memory_budget {
+            if !budget.try_reserve(&line) {
+                return;
+            }
+        }
+
+        let sent = match self.micro_batch {
+            Some((max_lines, max_delay)) => {
+                self.buffer_line((ts, level, line, scope, metadata), max_lines, max_delay)
+            }
+            None => self
+                .tx
+                .send(LokiTaskMsg::Log(ts, level, line, scope, metadata)),
+        };
+        if self.never_panic && sent.is_err() {
+            self.status.healthy.store(false, Ordering::Relaxed);
+            return;
+        }
+        sent.expect("The other thread should be running.");
+    }
+
+    // Add a line to the micro batch of this thread, and hand the batch over if it is full or old
+    // enough. Otherwise, the background thread collects it once it is old enough.
+    fn buffer_line(
+        &self,
+        entry: LogEntry,
+        max_lines: usize,
+        max_delay: Duration,
+    ) -> Result<(), SendError> {
+        let mut entry = Some(entry);
+        let full = MICRO_BATCH.try_with(|current| {
+            let mut current = current.borrow_mut();
+            let batch = match &*current {
+                Some((owner, batch)) if *owner == self.id => batch,
+                // the batch of another logger stays with that logger's background thread
+                _ => {
+                    let batch = Arc::new(Mutex::new(MicroBatch::default()));
+                    self.micro_batches
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .push(Arc::clone(&batch));
+                    &current.insert((self.id, batch)).1
+                }
+            };
+
+            let mut batch = batch.lock().unwrap_or_else(PoisonError::into_inner);
+            batch.push(entry.take().expect("The line is only taken once"));
+            let full = batch.lines.len() >= max_lines || batch.is_due(max_delay);
+            full.then(|| batch.take())
+        });
+
+        match full {
+            Ok(Some(lines)) => self.tx.send(LokiTaskMsg::LogBatch(lines)),
+            Ok(None) => Ok(()),
+            // the thread is exiting, so the line is sent on its own
+            Err(_) => self
+                .tx
+                .send(LokiTaskMsg::LogBatch(entry.into_iter().collect())),
+        }
+    }
+
+    /// Installs the logger as the default logger for the entire program.
+    /// Calling this (or any similar function from other libraries) more than once is a bug.
+    pub fn apply(self) -> Result<(), SetLoggerError> {
+        set_max_level(self.level_filter.min(self.directives.max_level()));
+        set_boxed_logger(Box::from(self))
+    }
+}
+
+thread_local! {
+    // labels of the label scopes that are active on this thread, innermost last
+    static LABEL_SCOPES: RefCell<Vec<HashMap<String, String>>> = const { RefCell::new(Vec::new()) };
+    // the micro batch of this thread for LokiBuilder::micro_batch, and the logger it belongs to
+    static MICRO_BATCH: RefCell<Option<(u64, Arc<Mutex<MicroBatch>>)>> = const { RefCell::new(None) };
+}
+
+// Merge the labels of the active label scopes of this thread, if there are any
+fn current_scope_labels() -> Option<ScopeLabels> {
+    LABEL_SCOPES.with(|scopes| {
+        let scopes = scopes.borrow();
+        if scopes.is_empty() {
+            return None;
+        }
+
+        let mut merged = BTreeMap::new();
+        for scope in scopes.iter() {
+            merged.extend(scope.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+        Some(merged.into_iter().collect())
+    })
+}
+
+/// `LabelScopeGuard` removes the labels added by `Loki::label_scope` when it is dropped. It must
+/// be dropped on the thread it was created on.
+#[must_use = "The labels are removed as soon as the guard is dropped."]
+pub struct LabelScopeGuard {
+    // the scope belongs to the current thread
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for LabelScopeGuard {
+    fn drop(&mut self) {
+        LABEL_SCOPES.with(|scopes| scopes.borrow_mut().pop());
+    }
+}
+
+/// `LokiHandle` is a cheap, cloneable reference to a `Loki` logger, for use after the logger was
+/// moved into the `log` crate by `Loki::apply`. Create one with `Loki::handle`.
+#[derive(Clone)]
+pub struct LokiHandle {
+    tx: Sender<LokiTaskMsg>,
+    memory_budget: Option<Arc<MemoryBudget>>,
+}
+
+impl LokiHandle {
+    /// Sends an already rendered line with the given timestamp (in nanoseconds since the Unix
+    /// epoch), bypassing filtering, middlewares, and the formatter. The line is batched, routed,
+    /// and retried like a log of level `Info`. Useful for bridging other log sources into Loki.
+    pub fn log_raw(&self, timestamp: u128, line: String) {
+        if let Some(budget) = &self.memory_budget {
+            if !budget.try_reserve(&line) {
+                return;
+            }
+        }
 
         self.tx
-            .send(LokiTaskMsg::Log(now, s))
+            .send(LokiTaskMsg::Log(timestamp, Level::Info, line, None, None))
             .expect("The other thread should be running.");
     }
 
+    /// Shuts the logger down like `Loki::shutdown`.
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        let (tx, rx) = bounded(1);
+        if self.tx.send(LokiTaskMsg::Shutdown(tx)).is_err() {
+            return false;
+        }
+        // a timeout too large to represent means waiting for as long as it takes
+        match Instant::now().checked_add(timeout) {
+            Some(_) => rx.recv_timeout(timeout).is_ok(),
+            None => rx.recv().is_ok(),
+        }
+    }
+
+    /// Changes the maximum number of lines per batch at runtime, like `LokiBuilder::max_logs`.
+    /// Batches that already hold this many lines are sent right away. With
+    /// `LokiBuilder::adaptive_batch_sizing`, the value is clamped to its bounds, and adaptive sizing
+    /// keeps adjusting the limit from there.
+    pub fn set_max_logs(&self, lines: usize) {
+        self.tx
+            .send(LokiTaskMsg::SetMaxLogs(lines))
+            .expect("The other thread should be running.");
+    }
+
+    /// Changes how long lines may wait in a batch at runtime, like
+    /// `LokiBuilder::max_log_lifetime`, e.g. to lower latency during an incident.
+    pub fn set_max_lifetime(&self, lifetime: Duration) {
+        self.tx
+            .send(LokiTaskMsg::SetMaxLifetime(lifetime))
+            .expect("The other thread should be running.");
+    }
+}
+
+// Copy of the record with its key value pairs replaced by `kvs`
+#[cfg(feature = "kv_unstable")]
+fn with_fields<'a>(record: &Record<'a>, kvs: &'a dyn log::kv::Source) -> Record<'a> {
+    Record::builder()
+        .args(*record.args())
+        .level(record.level())
+        .target(record.target())
+        .module_path(record.module_path())
+        .file(record.file())
+        .line(record.line())
+        .key_values(kvs)
+        .build()
+}
+
+// Collects the key value pairs of a record as strings
+#[cfg(feature = "kv_unstable")]
+struct KvCollector<'a>(&'a mut StructuredMetadata);
+
+#[cfg(feature = "kv_unstable")]
+impl<'a, 'kvs> log::kv::Visitor<'kvs> for KvCollector<'a> {
+    fn visit_pair(
+        &mut self,
+        key: log::kv::Key<'kvs>,
+        value: log::kv::Value<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+// The key value pairs of a record, except for those with the given keys
+#[cfg(feature = "kv_unstable")]
+struct WithoutKeys<'a>(&'a dyn log::kv::Source, &'a [String]);
+
+#[cfg(feature = "kv_unstable")]
+impl<'a> log::kv::Source for WithoutKeys<'a> {
+    fn visit<'kvs>(
+        &'kvs self,
+        visitor: &mut dyn log::kv::Visitor<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        self.0.visit(&mut SkipKeys(visitor, self.1))
+    }
+}
+
+// Passes the pairs on to another visitor, except for those with the given keys
+#[cfg(feature = "kv_unstable")]
+struct SkipKeys<'a, 'v, 'kvs>(&'v mut dyn log::kv::Visitor<'kvs>, &'a [String]);
+
+#[cfg(feature = "kv_unstable")]
+impl<'a, 'v, 'kvs> log::kv::Visitor<'kvs> for SkipKeys<'a, 'v, 'kvs> {
+    fn visit_pair(
+        &mut self,
+        key: log::kv::Key<'kvs>,
+        value: log::kv::Value<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        if self.1.iter().any(|k| k == key.as_str()) {
+            return Ok(());
+        }
+        self.0.visit_pair(key, value)
+    }
+}
+
+// Future returned by `Loki::flush_async`
+struct FlushFuture(Arc<FlushSignal>);
+
+impl Future for FlushFuture {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.0.poll_complete(cx)
+    }
+}
+
+impl Log for Loki {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_filter && self.directives.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let remapped = self.level_remaps.iter().find(|(from, _, prefix)| {
+            *from == record.level()
+                && prefix
+                    .as_ref()
+                    .is_none_or(|p| record.target().starts_with(p.as_str()))
+        });
+        if let Some((_, to, _)) = remapped {
+            let mut builder = Record::builder();
+            builder
+                .args(*record.args())
+                .level(*to)
+                .target(record.target())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line());
+            #[cfg(feature = "kv_unstable")]
+            builder.key_values(record.key_values());
+            self.send_record(&builder.build());
+        } else {
+            self.send_record(record);
+        }
+    }
+
+    /// Blocks until every log sent before this call has been pushed to Loki (or failed to push).
+    /// Logs reach the background thread in the order they were sent, so everything logged before
+    /// `flush()` is part of the batch that gets submitted.
+    /// Gives up after the time set by `LokiBuilder::flush_timeout`.
     fn flush(&self) {
+        // The mutex guards the number of flushes requested and completed. Flushes are requested
+        // while holding it, so they are completed in the order of the counter. A panic elsewhere
+        // while holding it can't corrupt anything.
         let (mtx, cvar) = &*self.flush_notif;
-        let mut flushed = mtx.lock().unwrap();
+        let mut flushes = mtx.lock().unwrap_or_else(PoisonError::into_inner);
 
-        self.tx
-            .send(LokiTaskMsg::Flush)
-            .expect("The other thread should be running");
+        let sent = self.tx.send(LokiTaskMsg::Flush);
+        if self.never_panic && sent.is_err() {
+            self.status.healthy.store(false, Ordering::Relaxed);
+            return;
+        }
+        sent.expect("The other thread should be running");
 
-        *flushed = false;
+        flushes.0 += 1;
+        let requested = flushes.0;
+        // a timeout too large to represent means waiting for as long as it takes
+        let deadline = Instant::now().checked_add(self.flush_timeout);
 
-        while !*flushed {
-            flushed = cvar.wait(flushed).unwrap();
+        while flushes.1 < requested {
+            let now = Instant::now();
+            let deadline = match deadline {
+                Some(deadline) => deadline,
+                None => {
+                    flushes = cvar.wait(flushes).unwrap_or_else(PoisonError::into_inner);
+                    continue;
+                }
+            };
+            if now >= deadline {
+                self.diagnostics.report(format_args!(
+                    "Flush timed out after {:?}, continuing without waiting for it...",
+                    self.flush_timeout
+                ));
+                return;
+            }
+            flushes = cvar
+                .wait_timeout(flushes, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
         }
     }
 }
+
+/// Installs a panic hook that flushes the global logger before running the previously installed
+/// panic hook, so that the logs leading up to a panic reach Loki. The flush is abandoned after
+/// `timeout` so that an unreachable Loki can't hold up the panic. Panics on the logger's own
+/// background thread skip the flush.
+pub fn flush_on_panic(timeout: Duration) {
+    let prev = take_hook();
+
+    set_hook(Box::new(move |info| {
+        match current().name() {
+            // a failed flush is caught below and must not start another one
+            Some(PANIC_FLUSH_THREAD_NAME) => return,
+            Some(TASK_THREAD_NAME) => {}
+            _ => {
+                // flush from another thread so that the timeout can be enforced
+                let (tx, rx) = bounded::<()>(1);
+                let flusher = Builder::new()
+                    .name(PANIC_FLUSH_THREAD_NAME.to_owned())
+                    .spawn(move || {
+                        let _ = catch_unwind(|| logger().flush());
+                        let _ = tx.send(());
+                    });
+                if flusher.is_ok() {
+                    let _ = rx.recv_timeout(timeout);
+                }
+            }
+        }
+
+        prev(info);
+    }));
+}
@@ -6,7 +6,7 @@ License, v. 2.0. If a copy of the MPL was not distributed with this
 file, You can obtain one at http://mozilla.org/MPL/2.0/.
 */
 
-use crate::LokiFormatter;
+use crate::{FormatAction, LokiFormatter};
 use bitflags::bitflags;
 #[cfg(feature = "kv_unstable")]
 use log::kv::{value::Error as LogError, Key, Value, Visitor};
@@ -26,6 +26,39 @@ const INVALID_KEY_CHARS: &[char] = &[' ', '=', '"'];
 pub struct LogfmtFormatter {
     include_fields: LogfmtAutoFields,
     escape_newlines: bool,
+    collision_policy: LogfmtCollisionPolicy,
+    always_quote_values: bool,
+    control_char_policy: ControlCharPolicy,
+}
+
+/// `LogfmtCollisionPolicy` specifies what the `LogfmtFormatter` does when a structured logging key
+/// has the same name as one of the automatically inserted fields, such as `level` or `message`.
+#[derive(PartialEq, Debug, Clone, Copy, Eq, Default)]
+pub enum LogfmtCollisionPolicy {
+    /// The automatically inserted field is kept and the key value pair is dropped
+    #[default]
+    PreferAuto,
+    /// The key value pair is kept and the automatically inserted field is dropped
+    PreferKv,
+    /// Both are kept, and the key value pair is renamed by appending `_1`, `_2`, etc. to its key
+    Rename,
+}
+
+/// `ControlCharPolicy` specifies how the `LogfmtFormatter` writes control characters in values.
+#[derive(PartialEq, Debug, Clone, Copy, Eq, Default)]
+pub enum ControlCharPolicy {
+    /// Each control character is written as a unicode escape, such as `\u{0}`
+    #[default]
+    Escape,
+    /// Each run of consecutive control characters is replaced by a single U+FFFD replacement
+    /// character, which keeps binary-ish payloads from bloating the line
+    Placeholder,
+    /// Control characters are removed
+    Strip,
+    /// Records with a control character in any of their fields are dropped entirely, so nothing
+    /// that could be used for log injection reaches Loki. `LogfmtFormatter::write_record` fails
+    /// for such records, while `LogfmtFormatter::format_record` asks the logger to drop them.
+    RejectLine,
 }
 
 impl LogfmtFormatter {
@@ -37,10 +70,51 @@ impl LogfmtFormatter {
         LogfmtFormatter {
             include_fields,
             escape_newlines,
+            collision_policy: LogfmtCollisionPolicy::PreferAuto,
+            always_quote_values: false,
+            control_char_policy: ControlCharPolicy::Escape,
         }
     }
 
-    /// Write a key value pair to the underlying string. Duplicate keys are dropped.
+    /// When enabled, every value is quoted, even if it doesn't contain any characters that
+    /// require quoting. Useful for strict logfmt parsers. By default, values are only quoted when
+    /// needed.
+    pub fn always_quote_values(mut self, enabled: bool) -> Self {
+        self.always_quote_values = enabled;
+        self
+    }
+
+    /// Specifies how structured logging keys that collide with automatically inserted fields are
+    /// handled. The default is `LogfmtCollisionPolicy::PreferAuto`.
+    pub fn collision_policy(mut self, policy: LogfmtCollisionPolicy) -> Self {
+        self.collision_policy = policy;
+        self
+    }
+
+    /// Specifies how control characters other than `\r`, `\n`, and `\t` are written. The default is
+    /// `ControlCharPolicy::Escape`.
+    pub fn on_control_char(mut self, policy: ControlCharPolicy) -> Self {
+        self.control_char_policy = policy;
+        self
+    }
+
+    /// Write an automatically inserted field, unless a key value pair takes precedence over it.
+    fn write_auto_pair(
+        &self,
+        dst: &mut String,
+        used_fields: &mut HashSet<String>,
+        kv_keys: &HashSet<String>,
+        key: &str,
+        val: &str,
+    ) -> std::fmt::Result {
+        if kv_keys.contains(key) {
+            return Ok(());
+        }
+        self.write_pair(dst, used_fields, &mut key.to_owned(), val)
+    }
+
+    /// Write a key value pair to the underlying string. Duplicate keys are dropped, or renamed
+    /// under `LogfmtCollisionPolicy::Rename`.
     fn write_pair(
         &self,
         dst: &mut String,
@@ -48,30 +122,30 @@ impl LogfmtFormatter {
         key: &mut String,
         val: &str,
     ) -> std::fmt::Result {
-        // Normalize the key
-        key.retain(|c| {
-            for invalid_char in INVALID_KEY_CHARS {
-                if c == *invalid_char {
-                    return false;
-                }
-            }
-            true
-        });
-        if key.is_empty() {
-            key.push('_');
-        }
+        normalize_key(key);
 
         // ensure uniqueness of the key
         if used_fields.contains(key) {
-            return Ok(());
+            if self.collision_policy != LogfmtCollisionPolicy::Rename {
+                return Ok(());
+            }
+            let base = key.clone();
+            let mut n = 1;
+            while used_fields.contains(key) {
+                *key = format!("{}_{}", base, n);
+                n += 1;
+            }
         }
         used_fields.insert(key.clone());
 
         // reformat the value if needed
         let mut formatted_value = String::new();
         formatted_value.reserve(val.len() + 10);
-        let mut need_quotes = false;
+        let mut need_quotes = self.always_quote_values;
+        // whether the previous character was a control character replaced by a placeholder
+        let mut in_control_run = false;
         for chr in val.chars() {
+            let is_control = chr.is_control() && !matches!(chr, '\n' | '\r' | '\t');
             match chr {
                 '\\' | '"' => {
                     need_quotes = true;
@@ -93,14 +167,28 @@ impl LogfmtFormatter {
                     formatted_value.push(chr);
                 }
                 _ => {
-                    if !chr.is_control() {
+                    if !is_control {
                         formatted_value.push(chr);
                     } else {
-                        need_quotes = true;
-                        formatted_value.push_str(&chr.escape_unicode().to_string());
+                        match self.control_char_policy {
+                            ControlCharPolicy::Escape => {
+                                need_quotes = true;
+                                write!(formatted_value, "{}", chr.escape_unicode())?;
+                            }
+                            ControlCharPolicy::Placeholder => {
+                                need_quotes = true;
+                                if !in_control_run {
+                                    formatted_value.push(char::REPLACEMENT_CHARACTER);
+                                }
+                            }
+                            ControlCharPolicy::Strip => {}
+                            // format_record turns this into dropping the record
+                            ControlCharPolicy::RejectLine => return Err(std::fmt::Error),
+                        }
                     }
                 }
             }
+            in_control_run = is_control;
         }
         if need_quotes {
             formatted_value.push('"');
@@ -129,79 +217,105 @@ impl LogfmtFormatter {
     }
 }
 
+// Remove characters that may not appear in logfmt keys
+fn normalize_key(key: &mut String) {
+    key.retain(|c| {
+        for invalid_char in INVALID_KEY_CHARS {
+            if c == *invalid_char {
+                return false;
+            }
+        }
+        true
+    });
+    if key.is_empty() {
+        key.push('_');
+    }
+}
+
 impl LokiFormatter for LogfmtFormatter {
     fn write_record(&self, dst: &mut String, rec: &Record) -> std::fmt::Result {
         let mut used_fields: HashSet<String> = HashSet::new();
         used_fields.reserve(10);
 
+        // keys of the key value pairs that take precedence over the automatically inserted fields
+        #[allow(unused_mut)]
+        let mut kv_keys: HashSet<String> = HashSet::new();
+        #[cfg(feature = "kv_unstable")]
+        if self.collision_policy == LogfmtCollisionPolicy::PreferKv
+            && self.include_fields.contains(LogfmtAutoFields::EXTRA)
+        {
+            rec.key_values()
+                .visit(&mut KeyCollector { keys: &mut kv_keys })
+                .expect("This visitor should not return an error");
+        }
+
         if self.include_fields.contains(LogfmtAutoFields::LEVEL) {
-            self.write_pair(
+            self.write_auto_pair(
                 dst,
                 &mut used_fields,
-                &mut "level".to_owned(),
+                &kv_keys,
+                "level",
                 &rec.level().to_string().to_lowercase(),
             )?;
         }
 
         if self.include_fields.contains(LogfmtAutoFields::MESSAGE) && rec.args().to_string() != "" {
-            self.write_pair(
+            self.write_auto_pair(
                 dst,
                 &mut used_fields,
-                &mut "message".to_owned(),
+                &kv_keys,
+                "message",
                 &rec.args().to_string(),
             )?;
         }
 
         if self.include_fields.contains(LogfmtAutoFields::TARGET) && rec.target() != "" {
-            self.write_pair(
-                dst,
-                &mut used_fields,
-                &mut "target".to_owned(),
-                rec.target(),
-            )?;
+            self.write_auto_pair(dst, &mut used_fields, &kv_keys, "target", rec.target())?;
         }
 
+        // module_path() and file() return the value whether or not it is 'static, so there is no
+        // need to fall back to the _static variants
         if self.include_fields.contains(LogfmtAutoFields::MODULE_PATH) {
-            let module = {
-                if rec.module_path().is_some() {
-                    rec.module_path()
-                } else if rec.module_path_static().is_some() {
-                    rec.module_path_static()
-                } else {
-                    None
-                }
-            };
-
-            if let Some(m) = module {
-                self.write_pair(dst, &mut used_fields, &mut "module".to_owned(), m)?;
+            if let Some(m) = rec.module_path() {
+                self.write_auto_pair(dst, &mut used_fields, &kv_keys, "module", m)?;
             }
         }
 
-        if self.include_fields.contains(LogfmtAutoFields::FILE) {
-            let file = {
-                if rec.file().is_some() {
-                    rec.file()
-                } else if rec.file_static().is_some() {
-                    rec.file_static()
-                } else {
-                    None
+        // a module path without :: is the crate root, so the whole path is the crate name
+        if self.include_fields.contains(LogfmtAutoFields::CRATE) {
+            if let Some(c) = rec.module_path().and_then(|m| m.split("::").next()) {
+                if !c.is_empty() {
+                    self.write_auto_pair(dst, &mut used_fields, &kv_keys, "crate", c)?;
                 }
-            };
+            }
+        }
 
-            if let Some(f) = file {
-                self.write_pair(dst, &mut used_fields, &mut "file".to_owned(), f)?;
+        if self.include_fields.contains(LogfmtAutoFields::FILE) {
+            if let Some(f) = rec.file() {
+                self.write_auto_pair(dst, &mut used_fields, &kv_keys, "file", f)?;
             }
         }
 
         if self.include_fields.contains(LogfmtAutoFields::LINE) && rec.line().is_some() {
-            self.write_pair(
+            self.write_auto_pair(
                 dst,
                 &mut used_fields,
-                &mut "line".to_owned(),
+                &kv_keys,
+                "line",
                 &rec.line().unwrap().to_string(),
             )?;
         }
 
+        if self.include_fields.contains(LogfmtAutoFields::UPTIME) {
+            self.write_auto_pair(
+                dst,
+                &mut used_fields,
+                &kv_keys,
+                "uptime",
+                &format!("{:.3}", crate::uptime().as_secs_f64()),
+            )?;
+        }
+
         #[cfg(feature = "kv_unstable")]
         if self.include_fields.contains(LogfmtAutoFields::EXTRA) {
             rec.key_values()
@@ -210,11 +324,32 @@ impl LokiFormatter for LogfmtFormatter {
                     fmt: self,
                     used: &mut used_fields,
                 })
-                .expect("This visitor should not return an error");
+                // only fails for values rejected by ControlCharPolicy::RejectLine
+                .map_err(|_| std::fmt::Error)?;
         }
 
         Ok(())
     }
+
+    fn format_record(
+        &self,
+        dst: &mut String,
+        rec: &Record,
+    ) -> Result<FormatAction, std::fmt::Error> {
+        if self.control_char_policy != ControlCharPolicy::RejectLine {
+            return self.write_record(dst, rec).map(|()| FormatAction::Send);
+        }
+
+        // writing to a String doesn't fail otherwise, so an error means the record was rejected
+        let mut line = String::new();
+        match self.write_record(&mut line, rec) {
+            Ok(()) => {
+                dst.push_str(&line);
+                Ok(FormatAction::Send)
+            }
+            Err(_) => Ok(FormatAction::Drop),
+        }
+    }
 }
 
 #[cfg(feature = "kv_unstable")]
@@ -237,6 +372,22 @@ impl<'a, 'kvs> Visitor<'kvs> for LogfmtVisitor<'a> {
     }
 }
 
+// Collects the normalized keys of a record's key value pairs
+#[cfg(feature = "kv_unstable")]
+struct KeyCollector<'a> {
+    keys: &'a mut HashSet<String>,
+}
+
+#[cfg(feature = "kv_unstable")]
+impl<'a, 'kvs> Visitor<'kvs> for KeyCollector<'a> {
+    fn visit_pair(&mut self, key: Key<'kvs>, _: Value<'kvs>) -> Result<(), LogError> {
+        let mut key = key.to_string();
+        normalize_key(&mut key);
+        self.keys.insert(key);
+        Ok(())
+    }
+}
+
 bitflags! {
     /// `LogfmtAutoFields` is used to determine what fields of a log::Record should be rendered into
     /// the final logfmt string by the `LogfmtFormatter`. The default set is LEVEL | MESSAGE | MODULE_PATH
@@ -257,6 +408,11 @@ bitflags! {
         /// Include any extra fields specified via the structured logging API, if enabled.
         #[cfg(feature = "kv_unstable")]
         const EXTRA = 1 << 6;
+        /// Include a `crate` field containing the first segment of the module path
+        const CRATE = 1 << 7;
+        /// Include an `uptime` field containing the seconds since the first `Loki` of the process
+        /// was built, with millisecond precision
+        const UPTIME = 1 << 8;
     }
 }
 
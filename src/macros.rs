@@ -0,0 +1,32 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+/// Construct a `LokiBuilder` like `LokiBuilder::new`, adding a `service` label set to the name of
+/// the calling crate (`CARGO_PKG_NAME`) unless the labels already contain one.
+#[macro_export]
+macro_rules! loki_builder {
+    ($endpoint:expr, $labels:expr) => {{
+        let mut labels: ::std::collections::HashMap<String, String> = $labels;
+        labels
+            .entry("service".to_owned())
+            .or_insert_with(|| env!("CARGO_PKG_NAME").to_owned());
+        $crate::LokiBuilder::new($endpoint, labels)
+    }};
+}
+
+/// Adds a `version` label set to the version of the calling crate (`CARGO_PKG_VERSION`) to the
+/// given `LokiBuilder`. A different label name can be passed as the second argument.
+#[macro_export]
+macro_rules! with_version_label {
+    ($builder:expr) => {
+        $crate::with_version_label!($builder, "version")
+    };
+    ($builder:expr, $key:expr) => {
+        $builder.version_label($key, env!("CARGO_PKG_VERSION"))
+    };
+}
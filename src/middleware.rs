@@ -0,0 +1,29 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+use log::Record;
+
+/// `LogMiddleware` implementations inspect each enabled record before it is formatted, e.g. for
+/// redaction, enrichment or sampling. Middlewares run in the order they were added to the
+/// `LokiBuilder`, and each one sees the fields added by the ones before it.
+pub trait LogMiddleware: Send + Sync {
+    fn process(&self, rec: &Record) -> MiddlewareAction;
+}
+
+/// `MiddlewareAction` is returned by `LogMiddleware::process` to tell the logger what to do with
+/// the record.
+#[derive(PartialEq, Debug, Clone, Eq)]
+pub enum MiddlewareAction {
+    /// The record is passed on unchanged
+    Keep,
+    /// The record is dropped and later middlewares are skipped
+    Drop,
+    /// The given key value pairs are added to the record
+    #[cfg(feature = "kv_unstable")]
+    Modify(Vec<(String, String)>),
+}
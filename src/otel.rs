@@ -0,0 +1,105 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+use crate::LokiFormatter;
+#[cfg(feature = "kv_unstable")]
+use log::kv::{value::Error as LogError, Key, Value, Visitor};
+use log::{Level, Record};
+use serde_json::{Map, Value as JsonValue};
+
+/// `OtelFormatter` provides a `LokiFormatter` that marshals logs to JSON objects using the field
+/// names from the OpenTelemetry log data model (`body`, `severity_text`, `severity_number`,
+/// `trace_id`, `span_id`, and `attributes`). This keeps logs queryable alongside OTel-native
+/// sources in Grafana.
+/// If structured logging is enabled, the `trace_id` and `span_id` keys are lifted to the top level
+/// and all other key value pairs become attributes.
+/// See: <https://opentelemetry.io/docs/specs/otel/logs/data-model/>
+#[derive(Default, Debug)]
+pub struct OtelFormatter {}
+
+impl OtelFormatter {
+    /// Create a new `OtelFormatter`.
+    pub fn new() -> Self {
+        OtelFormatter {}
+    }
+}
+
+// Maps log levels to the first severity number of the matching OTel severity range
+fn severity_number(level: Level) -> u8 {
+    match level {
+        Level::Trace => 1,
+        Level::Debug => 5,
+        Level::Info => 9,
+        Level::Warn => 13,
+        Level::Error => 17,
+    }
+}
+
+impl LokiFormatter for OtelFormatter {
+    fn write_record(&self, dst: &mut String, rec: &Record) -> std::fmt::Result {
+        let mut obj = Map::new();
+        let mut attributes = Map::new();
+
+        obj.insert("body".to_owned(), rec.args().to_string().into());
+        obj.insert(
+            "severity_text".to_owned(),
+            rec.level().as_str().to_uppercase().into(),
+        );
+        obj.insert(
+            "severity_number".to_owned(),
+            severity_number(rec.level()).into(),
+        );
+
+        if let Some(m) = rec.module_path() {
+            attributes.insert("code.namespace".to_owned(), m.into());
+        }
+        if let Some(f) = rec.file() {
+            attributes.insert("code.filepath".to_owned(), f.into());
+        }
+        if let Some(l) = rec.line() {
+            attributes.insert("code.lineno".to_owned(), l.into());
+        }
+
+        #[cfg(feature = "kv_unstable")]
+        rec.key_values()
+            .visit(&mut OtelVisitor {
+                obj: &mut obj,
+                attributes: &mut attributes,
+            })
+            .expect("This visitor should not return an error");
+
+        if !attributes.is_empty() {
+            obj.insert("attributes".to_owned(), JsonValue::Object(attributes));
+        }
+
+        dst.push_str(&serde_json::to_string(&obj).map_err(|_| std::fmt::Error)?);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "kv_unstable")]
+struct OtelVisitor<'a> {
+    obj: &'a mut Map<String, JsonValue>,
+    attributes: &'a mut Map<String, JsonValue>,
+}
+
+#[cfg(feature = "kv_unstable")]
+impl<'a, 'kvs> Visitor<'kvs> for OtelVisitor<'a> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), LogError> {
+        match key.as_str() {
+            "trace_id" | "span_id" => {
+                self.obj.insert(key.to_string(), value.to_string().into());
+            }
+            _ => {
+                self.attributes
+                    .insert(key.to_string(), value.to_string().into());
+            }
+        }
+        Ok(())
+    }
+}
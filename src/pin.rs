@@ -0,0 +1,44 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+use ring::digest::{digest, SHA256};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, Error, ServerName};
+use std::time::SystemTime;
+
+// Accepts exactly one server certificate, identified by the SHA-256 digest of its DER encoding.
+// The CA chain, name, and expiry of the certificate are not checked, as pinning replaces them.
+pub struct PinnedCertVerifier {
+    fingerprint: [u8; 32],
+}
+
+impl PinnedCertVerifier {
+    pub fn new(fingerprint: [u8; 32]) -> PinnedCertVerifier {
+        PinnedCertVerifier { fingerprint }
+    }
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        if digest(&SHA256, &end_entity.0).as_ref() == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(Error::InvalidCertificateData(String::from(
+                "The server certificate doesn't match the pinned SHA-256 fingerprint",
+            )))
+        }
+    }
+}
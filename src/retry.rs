@@ -0,0 +1,33 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+use std::time::{Duration, Instant};
+
+/// `RetryScheduler` implementations decide when a failed log batch should be retried. This trait
+/// can be implemented to customize the backoff strategy, e.g. to add jitter. The `FailurePolicy`
+/// is applied first, so the scheduler is only consulted for batches the policy allows to be
+/// retried. The default scheduler is `ExponentialBackoff`.
+pub trait RetryScheduler: Send + Sync {
+    /// Returns when the batch should be retried, or `None` if it should be dropped instead.
+    /// `attempt` is the number of times the batch has failed so far, starting at 1, and `status`
+    /// is the HTTP status code returned by Loki, if the failure was caused by one.
+    fn next_retry(&self, attempt: usize, status: Option<u16>) -> Option<Instant>;
+}
+
+/// `ExponentialBackoff` retries failed batches after 2^attempt seconds, capped at 2^10 seconds.
+#[derive(Default, Debug)]
+pub struct ExponentialBackoff {}
+
+// Upper bound on the exponent of the backoff, which caps the backoff at about 17 minutes
+const MAX_BACKOFF_EXP: usize = 10;
+
+impl RetryScheduler for ExponentialBackoff {
+    fn next_retry(&self, attempt: usize, _status: Option<u16>) -> Option<Instant> {
+        Some(Instant::now() + Duration::from_secs(1 << attempt.min(MAX_BACKOFF_EXP)))
+    }
+}
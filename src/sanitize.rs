@@ -0,0 +1,75 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+use crate::{FormatAction, LokiFormatter};
+use log::Record;
+
+/// `SanitizeFormatter` wraps another `LokiFormatter` and replaces every character of its output
+/// that isn't allowed with a placeholder, for downstream parsers that only accept a restricted set
+/// of characters. By default, only printable ASCII is allowed (so newlines and tabs are replaced
+/// too) and the placeholder is `?`.
+pub struct SanitizeFormatter {
+    inner: Box<dyn LokiFormatter>,
+    allowed: Box<dyn Fn(char) -> bool + Send + Sync>,
+    placeholder: char,
+}
+
+impl SanitizeFormatter {
+    /// Create a new `SanitizeFormatter` that sanitizes the output of `inner`.
+    pub fn new(inner: Box<dyn LokiFormatter>) -> Self {
+        SanitizeFormatter {
+            inner,
+            allowed: Box::new(|c| c == ' ' || c.is_ascii_graphic()),
+            placeholder: '?',
+        }
+    }
+
+    /// Specifies which characters are allowed, replacing the default of printable ASCII.
+    pub fn allowed(mut self, allowed: impl Fn(char) -> bool + Send + Sync + 'static) -> Self {
+        self.allowed = Box::new(allowed);
+        self
+    }
+
+    /// Specifies the character that disallowed characters are replaced with. It is written as is,
+    /// so it should be allowed itself.
+    pub fn placeholder(mut self, placeholder: char) -> Self {
+        self.placeholder = placeholder;
+        self
+    }
+}
+
+impl LokiFormatter for SanitizeFormatter {
+    fn write_record(&self, dst: &mut String, rec: &Record) -> std::fmt::Result {
+        self.format_record(dst, rec).map(|_| ())
+    }
+
+    fn format_record(
+        &self,
+        dst: &mut String,
+        rec: &Record,
+    ) -> Result<FormatAction, std::fmt::Error> {
+        let mut line = String::new();
+        let action = self.inner.format_record(&mut line, rec)?;
+        if action == FormatAction::Drop {
+            return Ok(action);
+        }
+
+        if line.chars().all(&self.allowed) {
+            dst.push_str(&line);
+        } else {
+            dst.extend(line.chars().map(|c| {
+                if (self.allowed)(c) {
+                    c
+                } else {
+                    self.placeholder
+                }
+            }));
+        }
+        Ok(action)
+    }
+}
@@ -6,258 +6,1262 @@ License, v. 2.0. If a copy of the MPL was not distributed with this
 file, You can obtain one at http://mozilla.org/MPL/2.0/.
 */
 
-use crate::FailurePolicy;
+use crate::{
+    BacklogHook, BatchResultHook, Diagnostics, FailurePolicy, FormatAction, HeaderProvider,
+    LokiBuilder, LokiError, LokiFormatter, PushHook, PushInfo, RetryOrder, RetryScheduler,
+    StaleSamplePolicy, ValueShape, REQUEST_TIMEOUT,
+};
+#[cfg(feature = "compress")]
+use crate::{CompressionCodec, CompressionPolicy};
 use core::cmp::Reverse;
 use derivative::Derivative;
 #[cfg(feature = "compress")]
-use flate2::{write::GzEncoder, Compression};
-use kanal::{ReceiveErrorTimeout, Receiver};
-#[cfg(feature = "tls")]
-use rustls::ClientConfig;
+use flate2::{
+    read::ZlibDecoder,
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+use kanal::{ReceiveErrorTimeout, Receiver, Sender};
+use log::{Level, Record};
+use serde::ser::{SerializeMap, SerializeSeq, SerializeStruct, Serializer};
 use serde::Serialize;
-use serde_json::to_vec;
-use std::collections::{BinaryHeap, HashMap};
-#[cfg(feature = "compress")]
-use std::io::Write;
-use std::sync::{Arc, Condvar, Mutex};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde_json::{json, to_vec};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use ureq::{AgentBuilder, Error, Request};
 use url::Url;
 
+// Response bodies of failed pushes are cut off after this many bytes in error messages
+const MAX_ERROR_BODY: u64 = 256;
+
+// The longest the micro batches of LokiBuilder::micro_batch go unchecked
+const MICRO_BATCH_TICK: Duration = Duration::from_millis(250);
+
 // LokiTask is a background thread that is used to send logs to Loki in the background
 pub struct LokiTask {
     rx: Receiver<LokiTaskMsg>,
-    request: Request,
+    // requests[0] is sent to the default endpoint, the rest to endpoints registered with route_level
+    requests: Vec<Request>,
+    header_provider: Option<HeaderProvider>,
+    on_push: Option<PushHook>,
+    on_batch_result: Option<BatchResultHook>,
+    on_backlog_change: Option<BacklogHook>,
+    // (high, low) water marks of the backlog, in lines
+    backlog_thresholds: (usize, usize),
+    backlogged: bool,
+    // number of lines in the dlq
+    dlq_lines: usize,
+    level_routes: HashMap<Level, usize>,
     labels: HashMap<String, String>,
+    label_order: Option<Arc<[String]>>,
+    max_values_per_label: HashMap<String, usize>,
+    // a label whose value depends on the level of each line
+    retention_label: Option<(String, HashMap<Level, String>)>,
+    // the values seen so far for each label in max_values_per_label
+    label_values: HashMap<String, HashSet<String>>,
     max_log_lines: usize,
+    // target time to encode a batch, and the bounds of max_log_lines
+    adaptive_batch_sizing: Option<(Duration, usize, usize)>,
+    initial_batch_capacity: usize,
     max_log_lifetime: Duration,
     failure_policy: FailurePolicy,
-    flush_notif: Arc<(Mutex<bool>, Condvar)>,
+    retry_scheduler: Box<dyn RetryScheduler>,
+    retry_order: RetryOrder,
+    max_sample_age: Option<(Duration, StaleSamplePolicy)>,
+    retry_spacing: Option<Duration>,
+    retry_time_budget: Option<Duration>,
+    last_retry: Option<Instant>,
+    value_shape: ValueShape,
+    flush_notif: Arc<(Mutex<(u64, u64)>, Condvar)>,
+    // callers of `Loki::wait_quiescent` waiting for everything to be delivered (or given up on)
+    quiesce_waiters: Vec<Sender<()>>,
+    status: Arc<TaskStatus>,
+    unhealthy_after: usize,
+    consecutive_failures: usize,
+    // whether the last push failed in a way that is worth retrying, e.g. a timeout or a 503
+    unreachable: bool,
+    // the number of consecutive failures that open the circuit breaker, and for how long
+    circuit_breaker: Option<(usize, Duration)>,
+    // when the open circuit breaker lets the next push through
+    circuit_open_until: Option<Instant>,
+    outage_since: Option<Instant>,
+    fallback_file: Option<PathBuf>,
+    fallback_after: Duration,
+    fallback_max_batches: usize,
+    // the formatter of the shutdown summary, until the summary is sent
+    summary_formatter: Option<Arc<dyn LokiFormatter>>,
+    shutdown_phase_label: bool,
+    // whether Loki::shutdown was called
+    shutting_down: bool,
+    report_drops_to_loki: bool,
+    // batches and lines dropped while Loki was unreachable, and the reason of the last drop, to be
+    // reported once a push succeeds again
+    unreported_drops: (usize, usize, String),
+    // the lines rejected by the memory budget that were reported already
+    reported_rejections: usize,
+    dry_run: bool,
+    stream_shards: Option<u32>,
+    #[cfg(feature = "compress")]
+    compression: CompressionPolicy,
+    #[cfg(feature = "compress")]
+    compression_codec: CompressionCodec,
+    #[cfg(feature = "compress")]
+    compression_prealloc_percent: usize,
+    #[cfg(feature = "compress")]
+    compress_dlq: bool,
+    memory_budget: Option<Arc<MemoryBudget>>,
+    micro_batches: MicroBatches,
+    // the max_delay of LokiBuilder::micro_batch, if enabled
+    micro_batch_delay: Option<Duration>,
+    micro_batches_checked: Instant,
+    diagnostics: Arc<Diagnostics>,
+    next_batch_id: u64,
+    started: Instant,
+    delivered: usize,
+    dropped: usize,
 }
 
 impl LokiTask {
     pub fn new(
         rx: Receiver<LokiTaskMsg>,
-        flush_notif: Arc<(Mutex<bool>, Condvar)>,
-        endpoint: Url,
-        headers: HashMap<String, String>,
-        labels: HashMap<String, String>,
-        max_log_lines: usize,
-        max_log_lifetime: Duration,
-        failure_policy: FailurePolicy,
-        #[cfg(feature = "tls")] tls_config: Option<Arc<ClientConfig>>,
+        flush_notif: Arc<(Mutex<(u64, u64)>, Condvar)>,
+        status: Arc<TaskStatus>,
+        memory_budget: Option<Arc<MemoryBudget>>,
+        micro_batches: MicroBatches,
+        fmt: Arc<dyn LokiFormatter>,
+        b: LokiBuilder,
     ) -> LokiTask {
-        let mut agent_builder = AgentBuilder::new().timeout(Duration::from_secs(30));
+        let mut agent_builder = AgentBuilder::new().timeout(REQUEST_TIMEOUT);
 
         #[cfg(feature = "tls")]
-        if let Some(tls_config) = tls_config {
+        if let Some(tls_config) = b.tls_config {
             agent_builder = agent_builder.tls_config(tls_config);
         }
 
-        let agent = agent_builder.build();
-        let mut request = agent.request_url("POST", &endpoint);
-        for (k, v) in headers {
-            request = request.set(&k, &v);
+        if let Some(resolver) = b.resolver {
+            agent_builder = agent_builder.resolver(move |netloc: &str| resolver.resolve(netloc));
         }
-        request = request.set("Content-Type", "application/json; charset=utf-8");
-        #[cfg(feature = "compress")]
-        {
-            request = request.set("Content-Encoding", "gzip");
+
+        if let Some(max) = b.max_idle_connections {
+            agent_builder = agent_builder.max_idle_connections(max);
+        }
+
+        if !b.follow_redirects {
+            agent_builder = agent_builder.redirects(0);
+        }
+
+        if let Some(max) = b.max_idle_connections_per_host {
+            agent_builder = agent_builder.max_idle_connections_per_host(max);
+        }
+
+        // Requests cloned from this one share the agent's connection pool, so connections are
+        // kept alive and reused across pushes.
+        let agent = agent_builder.build();
+        let make_request = |endpoint: &Url| {
+            let mut request = agent.request_url("POST", endpoint);
+            for (k, v) in &b.headers {
+                request = request.set(k, v);
+            }
+            request = request.set(
+                "Content-Type",
+                b.content_type
+                    .as_deref()
+                    .unwrap_or("application/json; charset=utf-8"),
+            );
+            request
+        };
+
+        let mut endpoints = vec![b.endpoint.clone()];
+        let mut level_routes = HashMap::new();
+        for (level, endpoint) in &b.level_routes {
+            let route = match endpoints.iter().position(|e| e == endpoint) {
+                Some(route) => route,
+                None => {
+                    endpoints.push(endpoint.clone());
+                    endpoints.len() - 1
+                }
+            };
+            level_routes.insert(*level, route);
         }
+        let requests = endpoints.iter().map(make_request).collect();
 
         LokiTask {
             rx,
-            request,
-            labels,
-            max_log_lines,
-            max_log_lifetime,
-            failure_policy,
+            requests,
+            header_provider: b.header_provider,
+            on_push: b.on_push,
+            on_batch_result: b.on_batch_result,
+            on_backlog_change: b.on_backlog_change,
+            backlog_thresholds: b
+                .backlog_thresholds
+                .unwrap_or((b.max_log_lines * 4, b.max_log_lines)),
+            backlogged: false,
+            dlq_lines: 0,
+            level_routes,
+            labels: b.labels,
+            label_order: b.preserve_label_order.then(|| b.label_order.into()),
+            max_values_per_label: b.max_values_per_label,
+            retention_label: b.retention_label,
+            label_values: HashMap::new(),
+            max_log_lines: match b.adaptive_batch_sizing {
+                Some((_, min, max)) => b.max_log_lines.clamp(min, max),
+                None => b.max_log_lines,
+            },
+            adaptive_batch_sizing: b.adaptive_batch_sizing,
+            initial_batch_capacity: b.initial_batch_capacity.unwrap_or(b.max_log_lines),
+            max_log_lifetime: b.max_log_lifetime,
+            failure_policy: b.failure_policy,
+            retry_scheduler: b.retry_scheduler,
+            retry_order: b.retry_order,
+            max_sample_age: b.max_sample_age,
+            retry_spacing: b.retry_spacing,
+            retry_time_budget: b.retry_time_budget,
+            last_retry: None,
+            value_shape: b.value_shape,
             flush_notif,
+            quiesce_waiters: Vec::new(),
+            status,
+            unhealthy_after: b.unhealthy_after,
+            consecutive_failures: 0,
+            unreachable: false,
+            circuit_breaker: b.circuit_breaker,
+            circuit_open_until: None,
+            outage_since: None,
+            fallback_file: b.fallback_file,
+            fallback_after: b.fallback_after,
+            fallback_max_batches: b.fallback_max_batches,
+            summary_formatter: b.shutdown_summary.then_some(fmt),
+            shutdown_phase_label: b.shutdown_phase_label,
+            shutting_down: false,
+            report_drops_to_loki: b.report_drops_to_loki,
+            unreported_drops: (0, 0, String::new()),
+            reported_rejections: 0,
+            dry_run: b.dry_run,
+            stream_shards: b.stream_shards,
+            #[cfg(feature = "compress")]
+            compression: b.compression,
+            #[cfg(feature = "compress")]
+            compression_codec: b.compression_codec,
+            #[cfg(feature = "compress")]
+            compression_prealloc_percent: b.compression_prealloc_percent,
+            #[cfg(feature = "compress")]
+            compress_dlq: b.compress_dlq,
+            memory_budget,
+            micro_batches,
+            micro_batch_delay: b.micro_batch.map(|(_, max_delay)| max_delay),
+            micro_batches_checked: Instant::now(),
+            diagnostics: b.diagnostics,
+            next_batch_id: 0,
+            started: Instant::now(),
+            delivered: 0,
+            dropped: 0,
         }
     }
 
     // Thread loop.
     // Tries to receive messages from the channel, flushing before any limits are violated.
     // When not processing items from the channel, we'll retry failed items if there are any and check the age constraint.
-    pub fn run(&self) {
-        let mut lp = LokiPush {
-            streams: [LokiStream {
-                stream: self.labels.clone(),
-                values: Vec::with_capacity(self.max_log_lines),
-            }],
-            first: None,
-            failures: 0,
-        };
+    pub fn run(&mut self) {
+        // one pending batch per endpoint, followed by batches for labels from label scopes
+        let mut pushes: Vec<LokiPush> = (0..self.requests.len())
+            .map(|route| self.new_push(route, None, self.initial_batch_capacity))
+            .collect();
         let mut dlq: BinaryHeap<Reverse<FailedPush>> = BinaryHeap::new();
 
         loop {
             loop {
                 match self.rx.recv_timeout(Duration::from_millis(250)) {
                     Ok(msg) => {
+                        // lines buffered by micro batching are part of flushes, drains, and dumps
+                        if matches!(
+                            msg,
+                            LokiTaskMsg::Flush
+                                | LokiTaskMsg::RequestFlush
+                                | LokiTaskMsg::FlushAsync(_)
+                                | LokiTaskMsg::Drain(_)
+                                | LokiTaskMsg::Dump(_)
+                                | LokiTaskMsg::Quiesce(_)
+                                | LokiTaskMsg::Shutdown(_)
+                        ) {
+                            self.collect_micro_batches(&mut pushes, &mut dlq, true);
+                        }
                         match msg {
-                            LokiTaskMsg::Log(time, log_line) => {
-                                lp.streams[0].values.push([format!("{}", time), log_line]);
-                                if lp.streams[0].values.len() == self.max_log_lines {
-                                    self.submit_logs(&mut lp, &mut dlq);
-                                }
-                                if lp.first.is_none() {
-                                    lp.first = Some(time);
+                            LokiTaskMsg::Log(time, level, line, scope, metadata) => {
+                                self.add_line(
+                                    &mut pushes,
+                                    &mut dlq,
+                                    (time, level, line, scope, metadata),
+                                );
+                            }
+                            LokiTaskMsg::LogBatch(entries) => {
+                                for entry in entries {
+                                    self.add_line(&mut pushes, &mut dlq, entry);
                                 }
                             }
                             LokiTaskMsg::Flush => {
-                                self.submit_logs(&mut lp, &mut dlq);
-                                self.retry_all_failed(&mut dlq);
+                                self.flush(&mut pushes, &mut dlq);
 
                                 let (mtx, cvar) = &*self.flush_notif;
-                                let mut flushed = mtx.lock().unwrap();
-                                *flushed = true;
+                                let mut flushes =
+                                    mtx.lock().unwrap_or_else(PoisonError::into_inner);
+                                flushes.1 += 1;
                                 cvar.notify_all();
                             }
+                            // unlike Flush, nobody is waiting on the condvar for this one
+                            LokiTaskMsg::RequestFlush => {
+                                self.flush(&mut pushes, &mut dlq);
+                            }
+                            LokiTaskMsg::Quiesce(waiter) => {
+                                self.flush(&mut pushes, &mut dlq);
+                                self.quiesce_waiters.push(waiter);
+                            }
+                            LokiTaskMsg::Shutdown(reply) => {
+                                self.shut_down(&mut pushes, &mut dlq);
+                                self.shutting_down = true;
+                                let _ = reply.send(());
+                            }
+                            LokiTaskMsg::FlushAsync(signal) => {
+                                self.flush(&mut pushes, &mut dlq);
+                                signal.complete();
+                            }
+                            LokiTaskMsg::Dump(reply) => {
+                                let _ = reply.send(Self::dump(&pushes));
+                            }
+                            LokiTaskMsg::Drain(reply) => {
+                                let _ = reply.send(self.drain(&mut pushes, &mut dlq));
+                            }
+                            LokiTaskMsg::SetMaxLogs(lines) => {
+                                // adaptive sizing keeps adjusting the limit from here, within its
+                                // bounds
+                                let lines = match self.adaptive_batch_sizing {
+                                    Some((_, min, max)) => lines.clamp(min, max),
+                                    None => lines.max(1),
+                                };
+                                self.set_max_log_lines(lines);
+                                // batches that are already over the new limit are sent right away
+                                for lp in pushes.iter_mut() {
+                                    if lp.streams[0].values.len() >= self.max_log_lines {
+                                        self.submit_logs(lp, &mut dlq);
+                                    }
+                                }
+                            }
+                            LokiTaskMsg::SetMaxLifetime(lifetime) => {
+                                self.max_log_lifetime = lifetime;
+                                self.status.set_max_log_lifetime(lifetime);
+                            }
+                            LokiTaskMsg::SelfTest(reply) => {
+                                let _ = reply.send(self.self_test());
+                            }
+                            LokiTaskMsg::ExportDlq(path, reply) => {
+                                let _ = reply.send(self.export_dlq(&path, &mut dlq));
+                            }
                         }
+                        self.collect_micro_batches(&mut pushes, &mut dlq, false);
+                        self.check_backlog();
+                        self.notify_quiescent(&pushes, &dlq);
                         continue;
                     }
                     Err(ReceiveErrorTimeout::Timeout) => {
+                        self.collect_micro_batches(&mut pushes, &mut dlq, false);
+                        self.check_backlog();
+                        self.notify_quiescent(&pushes, &dlq);
                         break;
                     }
                     // This matches Closed and SendClosed
                     Err(_) => {
-                        self.submit_logs(&mut lp, &mut dlq);
-                        self.retry_all_failed(&mut dlq);
+                        self.collect_micro_batches(&mut pushes, &mut dlq, true);
+                        self.shut_down(&mut pushes, &mut dlq);
                         return;
                     }
                 }
             }
 
-            if let Some(first_timestamp) = lp.first {
-                let time = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .expect("The current moment is beyond the UNIX Epoch")
-                    .as_nanos();
+            let time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("The current moment is beyond the UNIX Epoch")
+                .as_nanos();
 
-                if time - first_timestamp > self.max_log_lifetime.as_nanos() {
-                    self.submit_logs(&mut lp, &mut dlq);
-                    continue;
+            let mut submitted = false;
+            for lp in pushes.iter_mut() {
+                if let Some(first_timestamp) = lp.first {
+                    if time.saturating_sub(first_timestamp) > self.max_log_lifetime.as_nanos() {
+                        self.submit_logs(lp, &mut dlq);
+                        submitted = true;
+                    }
                 }
             }
+            // batches for label scopes that have been sent are recreated when needed
+            pushes.retain(|lp| lp.scope.is_none() || lp.first.is_some());
+            if submitted {
+                continue;
+            }
 
-            while self.retry_failed(&mut dlq) {}
+            let retries_started = Instant::now();
+            while self.retry_failed(&mut dlq) {
+                if let Some(budget) = self.retry_time_budget {
+                    if retries_started.elapsed() >= budget {
+                        break;
+                    }
+                }
+            }
+            self.notify_quiescent(&pushes, &dlq);
         }
     }
 
-    // Send the push off to the server.
-    fn submit_logs(&self, lp: &mut LokiPush, dlq: &mut BinaryHeap<Reverse<FailedPush>>) {
+    // Wake up the callers of `Loki::wait_quiescent` once there is nothing left to deliver, including
+    // lines that other threads buffered for micro batching.
+    fn notify_quiescent(&mut self, pushes: &[LokiPush], dlq: &BinaryHeap<Reverse<FailedPush>>) {
+        // waiters that timed out are gone, so they don't pile up during a long outage
+        self.quiesce_waiters
+            .retain(|waiter| !waiter.is_disconnected());
+        if self.quiesce_waiters.is_empty()
+            || !self.rx.is_empty()
+            || !dlq.is_empty()
+            || pushes.iter().any(|lp| lp.first.is_some())
+        {
+            return;
+        }
+        let buffered = self
+            .micro_batches
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .any(|b| {
+                !b.lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .lines
+                    .is_empty()
+            });
+        if buffered {
+            return;
+        }
+        for waiter in self.quiesce_waiters.drain(..) {
+            let _ = waiter.send(());
+        }
+    }
+
+    // Add a line to the batch for its route and label scope, and submit the batch once it's full.
+    fn add_line(
+        &mut self,
+        pushes: &mut Vec<LokiPush>,
+        dlq: &mut BinaryHeap<Reverse<FailedPush>>,
+        entry: LogEntry,
+    ) {
+        let (mut time, level, log_line, scope, metadata) = entry;
+        let route = self.level_routes.get(&level).copied().unwrap_or(0);
+        let scope = self.with_shutdown_phase(self.with_retention_label(level, scope));
+        let idx = match scope {
+            None => route,
+            Some(mut scope) => {
+                self.cap_label_values(&mut scope);
+                pushes
+                    .iter()
+                    .position(|lp| lp.route == route && lp.scope.as_ref() == Some(&scope))
+                    .unwrap_or_else(|| {
+                        // scopes tend to be short-lived and few lines long, so their
+                        // batches grow as needed
+                        pushes.push(self.new_push(route, Some(scope), 0));
+                        pushes.len() - 1
+                    })
+            }
+        };
+        let lp = &mut pushes[idx];
+        // Loki drops entries whose timestamp and line match an earlier one, so lines logged in the
+        // same tick are spread out after the previous line. Lines logged earlier than the previous
+        // line, e.g. after the clock went back, keep their timestamp.
+        let logged = time;
+        if let Some(last) = lp.streams[0].values.last() {
+            if time >= lp.last_logged && time <= last.ts {
+                time = last.ts + 1;
+            }
+        }
+        lp.last_logged = logged;
+        lp.streams[0].values.push(LokiValue {
+            ts: time,
+            line: log_line,
+            metadata,
+        });
         if lp.first.is_none() {
+            lp.first = Some(time);
+            lp.id = self.next_batch_id;
+            self.next_batch_id += 1;
+            if let Some(shards) = self.stream_shards {
+                let shard = (time / 60_000_000_000) % u128::from(shards);
+                lp.streams[0]
+                    .stream
+                    .insert(String::from("shard"), shard.to_string());
+            }
+        }
+        if lp.streams[0].values.len() >= self.max_log_lines {
+            self.submit_logs(lp, dlq);
+        }
+    }
+
+    // Move the lines buffered by micro batching into batches. Unless `all` is set, only the groups
+    // whose first line is older than the delay are taken, and the buffers are only checked as
+    // often as the delay requires.
+    fn collect_micro_batches(
+        &mut self,
+        pushes: &mut Vec<LokiPush>,
+        dlq: &mut BinaryHeap<Reverse<FailedPush>>,
+        all: bool,
+    ) {
+        let max_delay = match self.micro_batch_delay {
+            Some(delay) => delay,
+            None => return,
+        };
+        if !all && self.micro_batches_checked.elapsed() < max_delay.min(MICRO_BATCH_TICK) {
             return;
         }
+        self.micro_batches_checked = Instant::now();
 
-        // serialize json object
-        #[allow(unused_mut)]
-        let mut serialized = match to_vec(lp) {
-            Ok(v) => v,
-            Err(e) => {
-                self.fail(lp, dlq, &e.to_string(), false);
-                return;
+        let mut entries = Vec::new();
+        {
+            let mut batches = self
+                .micro_batches
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            batches.retain(|shared| {
+                let mut batch = shared.lock().unwrap_or_else(PoisonError::into_inner);
+                if all || batch.is_due(max_delay) {
+                    entries.append(&mut batch.take());
+                }
+                // the buffers of threads that exited are dropped once they are empty
+                !batch.lines.is_empty() || Arc::strong_count(shared) > 1
+            });
+        }
+        for entry in entries {
+            self.add_line(pushes, dlq, entry);
+        }
+    }
+
+    // Tell the backlog hook, if any, when the backlog crosses one of the thresholds.
+    fn check_backlog(&mut self) {
+        let hook = match &self.on_backlog_change {
+            Some(hook) => hook,
+            None => return,
+        };
+
+        let backlog = self.rx.len() + self.dlq_lines;
+        let (high, low) = self.backlog_thresholds;
+        if !self.backlogged && backlog >= high {
+            self.backlogged = true;
+            hook(true);
+        } else if self.backlogged && backlog <= low {
+            self.backlogged = false;
+            hook(false);
+        }
+    }
+
+    // Replace the values of capped labels that are over their limit with `other`.
+    fn cap_label_values(&mut self, scope: &mut ScopeLabels) {
+        for (k, v) in scope.iter_mut() {
+            let max = match self.max_values_per_label.get(k) {
+                Some(max) => *max,
+                None => continue,
+            };
+            let seen = self.label_values.entry(k.clone()).or_default();
+            if seen.contains(v) {
+                continue;
+            }
+            if seen.len() < max {
+                seen.insert(v.clone());
+            } else {
+                *v = String::from("other");
             }
+        }
+    }
+
+    // Add the phase="shutdown" label to the scope once Loki::shutdown was called, if enabled.
+    fn with_shutdown_phase(&self, scope: Option<ScopeLabels>) -> Option<ScopeLabels> {
+        if !(self.shutdown_phase_label && self.shutting_down) {
+            return scope;
+        }
+
+        let mut scope = scope.unwrap_or_default();
+        // scope labels are sorted by name
+        if let Err(i) = scope.binary_search_by(|(k, _)| k.as_str().cmp("phase")) {
+            scope.insert(i, (String::from("phase"), String::from("shutdown")));
+        }
+        Some(scope)
+    }
+
+    // Add the retention label for the level to the scope, unless the scope already sets it.
+    fn with_retention_label(
+        &self,
+        level: Level,
+        scope: Option<ScopeLabels>,
+    ) -> Option<ScopeLabels> {
+        let (key, value) = match &self.retention_label {
+            Some((key, values)) => match values.get(&level) {
+                Some(value) => (key, value),
+                None => return scope,
+            },
+            None => return scope,
         };
 
-        // perform gzip compression
+        let mut scope = scope.unwrap_or_default();
+        // scope labels are sorted by name
+        if let Err(i) = scope.binary_search_by(|(k, _)| k.as_str().cmp(key)) {
+            scope.insert(i, (key.clone(), value.clone()));
+        }
+        Some(scope)
+    }
+
+    // Create an empty batch for the given route, with the labels of a label scope if given, and room
+    // for `capacity` lines.
+    fn new_push(&self, route: usize, scope: Option<ScopeLabels>, capacity: usize) -> LokiPush {
+        let mut stream = self.labels.clone();
+        if let Some(scope) = &scope {
+            stream.extend(scope.iter().cloned());
+        }
+
+        LokiPush {
+            streams: [LokiStream {
+                stream,
+                order: self.label_order.clone(),
+                values: Vec::with_capacity(capacity),
+                shape: self.value_shape,
+            }],
+            first: None,
+            last_logged: 0,
+            id: 0,
+            failures: 0,
+            first_failed: None,
+            route,
+            scope,
+        }
+    }
+
+    // Submit every pending batch and retry everything in the dlq.
+    fn flush(&mut self, pushes: &mut [LokiPush], dlq: &mut BinaryHeap<Reverse<FailedPush>>) {
+        for lp in pushes.iter_mut() {
+            self.submit_logs(lp, dlq);
+        }
+        self.retry_all_failed(dlq);
+    }
+
+    // Copy the lines of the pending batches, without removing them.
+    fn dump(pushes: &[LokiPush]) -> Vec<String> {
+        let mut values: Vec<&LokiValue> =
+            pushes.iter().flat_map(|lp| &lp.streams[0].values).collect();
+        values.sort_by_key(|v| v.ts);
+        values.into_iter().map(|v| v.line.clone()).collect()
+    }
+
+    // Remove every undelivered line from the pending batches and the dlq.
+    fn drain(
+        &mut self,
+        pushes: &mut [LokiPush],
+        dlq: &mut BinaryHeap<Reverse<FailedPush>>,
+    ) -> Vec<(u128, String)> {
+        let mut drained = Vec::new();
+        let mut failed: Vec<LokiPush> = dlq.drain().map(|v| *v.0.into_push()).collect();
+        self.dlq_lines = 0;
+
+        for lp in pushes.iter_mut().chain(failed.iter_mut()) {
+            if lp.first.is_some() {
+                self.report(lp, Err("drained"));
+            }
+            self.release(lp);
+            lp.first = None;
+            drained.extend(lp.streams[0].values.drain(..).map(|v| (v.ts, v.line)));
+        }
+
+        drained.sort_by_key(|(ts, _)| *ts);
+        drained
+    }
+
+    // Apply the compression policy to a serialized push. Returns the body to send, along with the
+    // value for the Content-Encoding header if the body was compressed.
+    fn encode(&self, serialized: Vec<u8>) -> std::io::Result<(Vec<u8>, Option<&'static str>)> {
         #[cfg(feature = "compress")]
-        {
-            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-            match encoder.write_all(&serialized) {
-                Ok(()) => match encoder.finish() {
-                    Ok(w) => {
-                        serialized = w;
-                    }
-                    Err(e) => {
-                        self.fail(lp, dlq, &e.to_string(), false);
+        if match self.compression {
+            CompressionPolicy::Always => true,
+            CompressionPolicy::Never => false,
+            CompressionPolicy::AboveBytes(n) => serialized.len() > n,
+        } {
+            let buf = Vec::with_capacity(
+                serialized
+                    .len()
+                    .saturating_mul(self.compression_prealloc_percent)
+                    / 100,
+            );
+            return match self.compression_codec {
+                CompressionCodec::Gzip => {
+                    let mut encoder = GzEncoder::new(buf, Compression::default());
+                    encoder.write_all(&serialized)?;
+                    Ok((encoder.finish()?, Some("gzip")))
+                }
+                CompressionCodec::Deflate => {
+                    let mut encoder = ZlibEncoder::new(buf, Compression::default());
+                    encoder.write_all(&serialized)?;
+                    Ok((encoder.finish()?, Some("deflate")))
+                }
+            };
+        }
+
+        Ok((serialized, None))
+    }
+
+    // Send the push off to the server.
+    fn submit_logs(&mut self, lp: &mut LokiPush, dlq: &mut BinaryHeap<Reverse<FailedPush>>) {
+        let first = match lp.first {
+            Some(first) => first,
+            None => return,
+        };
+
+        // don't bother sending samples that Loki would reject for being too old
+        if let Some((max_age, policy)) = self.max_sample_age {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("The current moment is beyond the Unix Epoch.")
+                .as_nanos();
+
+            if first.saturating_add(max_age.as_nanos()) < now {
+                match policy {
+                    StaleSamplePolicy::Drop => {
+                        self.diagnostics.report(format_args!("Batch of {} logs is older than the max sample age of {:?}, dropping...", lp.streams[0].values.len(), max_age));
+                        self.drop_batch(lp, "older than the max sample age");
+                        lp.streams[0].values.clear();
+                        lp.first = None;
                         return;
                     }
-                },
-                Err(e) => {
-                    self.fail(lp, dlq, &e.to_string(), false);
-                    return;
+                    StaleSamplePolicy::Rewrite => {
+                        for (i, v) in lp.streams[0].values.iter_mut().enumerate() {
+                            v.ts = now + i as u128;
+                        }
+                        lp.first = Some(now);
+                    }
                 }
             }
         }
 
-        // attempt to send the request
-        let result = self.request.clone().send_bytes(&serialized);
-        if result.is_err() {
-            match result.expect_err("We already checked if the result was an error.") {
-                Error::Status(code, resp) => {
-                    self.fail(
-                        lp,
-                        dlq,
-                        &format!("HTTP {}: {}", code, resp.status_text()),
-                        code == 408 || code == 429 || code >= 500,
-                    );
-                    return;
-                }
-                e => {
-                    self.fail(lp, dlq, &e.to_string(), true);
-                    return;
-                }
+        // while the circuit breaker is open, batches wait for it without using up an attempt
+        if let Some(until) = self.circuit_open_until {
+            if Instant::now() < until {
+                let lpc = lp.clone();
+                lp.streams[0].values.clear();
+                lp.first = None;
+                self.requeue(lpc, dlq, until);
+                return;
             }
         }
 
+        let (info, result) = self.post(lp);
+
+        if let (Some(info), Some(hook)) = (&info, &self.on_push) {
+            hook(info);
+        }
+        if let Some(info) = &info {
+            self.adapt_batch_size(info);
+        }
+
+        if let Err(e) = result {
+            let (transient, status) = match &e {
+                LokiError::Status(code, _) => {
+                    (*code == 408 || *code == 429 || *code >= 500, Some(*code))
+                }
+                LokiError::Transport(_) => (true, None),
+                LokiError::Encoding(_) | LokiError::Disconnected => (false, None),
+            };
+            self.fail(lp, dlq, &e.to_string(), transient, status);
+            self.trip_circuit_breaker();
+            return;
+        }
+
+        if self.circuit_open_until.take().is_some() {
+            self.diagnostics.report(format_args!(
+                "Loki is reachable again, closing the circuit breaker"
+            ));
+        }
+
+        self.delivered += lp.streams[0].values.len();
+        self.report(lp, Ok(()));
+        self.release(lp);
+        self.consecutive_failures = 0;
+        self.unreachable = false;
+        self.outage_since = None;
+        self.status.healthy.store(true, Ordering::Relaxed);
+        if self.report_drops_to_loki {
+            self.report_outage_drops(lp.route);
+        }
+
         // reset shared struct
         lp.streams[0].values.clear();
         lp.first = None;
     }
 
+    // Open the circuit breaker after too many consecutive failures, or reopen it if the push that
+    // probed Loki after the cooldown failed.
+    fn trip_circuit_breaker(&mut self) {
+        let (failures, cooldown) = match self.circuit_breaker {
+            Some(v) => v,
+            None => return,
+        };
+        if self.circuit_open_until.is_none() && self.consecutive_failures < failures {
+            return;
+        }
+
+        if self.circuit_open_until.is_none() {
+            self.diagnostics.report(format_args!(
+                "{} pushes in a row failed, opening the circuit breaker for {:?}",
+                self.consecutive_failures, cooldown
+            ));
+        } else {
+            self.diagnostics.report(format_args!(
+                "Loki is still unreachable, keeping the circuit breaker open for {:?}",
+                cooldown
+            ));
+        }
+        self.circuit_open_until = Some(Instant::now() + cooldown);
+    }
+
+    // Serialize, compress, and send a push, unless this is a dry run. Returns the details of the
+    // attempt for the push hook, unless it failed before sending, along with the outcome.
+    fn post(&self, lp: &LokiPush) -> (Option<PushInfo>, Result<(), LokiError>) {
+        let encode_started = Instant::now();
+        let serialized = match to_vec(lp) {
+            Ok(v) => v,
+            Err(e) => return (None, Err(LokiError::Encoding(e.to_string()))),
+        };
+
+        // compress the body, and label the request with whatever encoding was actually applied
+        let uncompressed_bytes = serialized.len();
+        let (serialized, encoding) = match self.encode(serialized) {
+            Ok(v) => v,
+            Err(e) => return (None, Err(LokiError::Encoding(e.to_string()))),
+        };
+        let mut info = PushInfo {
+            lines: lp.streams[0].values.len(),
+            uncompressed_bytes,
+            compressed_bytes: serialized.len(),
+            encode_duration: encode_started.elapsed(),
+            duration: Duration::ZERO,
+            success: false,
+        };
+
+        let mut request = self.requests[lp.route].clone();
+        if let Some(encoding) = encoding {
+            request = request.set("Content-Encoding", encoding);
+        }
+        if let Some(provider) = &self.header_provider {
+            for (k, v) in provider() {
+                request = request.set(&k, &v);
+            }
+        }
+
+        if self.dry_run {
+            info.success = true;
+            return (Some(info), Ok(()));
+        }
+
+        // The whole body is known up front, so send_bytes always sets Content-Length and never
+        // uses chunked transfer encoding, which some proxies reject. Without following redirects,
+        // ureq hands us the 3xx response as a success.
+        let started = Instant::now();
+        let sent = request.send_bytes(&serialized);
+        info.duration = started.elapsed();
+        let resp = match sent {
+            Ok(resp) if (300..400).contains(&resp.status()) => resp,
+            Ok(_) => {
+                info.success = true;
+                return (Some(info), Ok(()));
+            }
+            Err(Error::Status(_, resp)) => resp,
+            Err(e) => return (Some(info), Err(LokiError::Transport(e.to_string()))),
+        };
+
+        let code = resp.status();
+        let mut emsg = match resp.header("Location") {
+            Some(location) => format!("{} (redirected to {})", resp.status_text(), location),
+            None => resp.status_text().to_owned(),
+        };
+        // Loki explains why it rejected a push in the body, e.g. which label was invalid
+        let mut body = Vec::new();
+        let _ = resp
+            .into_reader()
+            .take(MAX_ERROR_BODY + 1)
+            .read_to_end(&mut body);
+        let truncated = body.len() as u64 > MAX_ERROR_BODY;
+        body.truncate(MAX_ERROR_BODY as usize);
+        let body = String::from_utf8_lossy(&body);
+        if !body.trim().is_empty() {
+            emsg.push_str(": ");
+            emsg.push_str(body.trim());
+            if truncated {
+                emsg.push_str("...");
+            }
+        }
+        (Some(info), Err(LokiError::Status(code, emsg)))
+    }
+
+    // Shrink max_log_lines if encoding the batch took too long, or grow it if a full batch was
+    // encoded well within the target.
+    fn adapt_batch_size(&mut self, info: &PushInfo) {
+        let (target, min, max) = match self.adaptive_batch_sizing {
+            Some(v) => v,
+            None => return,
+        };
+
+        if info.encode_duration > target {
+            self.set_max_log_lines((self.max_log_lines / 2).max(min));
+        } else if info.encode_duration < target / 2 && info.lines >= self.max_log_lines {
+            self.set_max_log_lines((self.max_log_lines + self.max_log_lines / 4 + 1).min(max));
+        }
+    }
+
+    // Change the batch limit, and let the logger know for its config summary.
+    fn set_max_log_lines(&mut self, lines: usize) {
+        self.max_log_lines = lines;
+        self.status.max_log_lines.store(lines, Ordering::Relaxed);
+    }
+
+    // Push a single test line to the default endpoint and report the outcome. Failures are not
+    // retried.
+    fn self_test(&self) -> Result<(), LokiError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("The current moment is beyond the Unix Epoch.")
+            .as_nanos();
+
+        let mut lp = self.new_push(0, None, 1);
+        lp.streams[0].values.push(LokiValue {
+            ts: now,
+            line: String::from("log_loki self test"),
+            metadata: None,
+        });
+        lp.first = Some(now);
+        self.post(&lp).1
+    }
+
+    // Flush everything, then send a final line summarizing the session if it wasn't sent yet. Lines
+    // still waiting for a retry afterwards are counted as pending, as they may yet be delivered.
+    fn shut_down(&mut self, pushes: &mut [LokiPush], dlq: &mut BinaryHeap<Reverse<FailedPush>>) {
+        self.flush(pushes, dlq);
+        let fmt = match self.summary_formatter.take() {
+            Some(fmt) => fmt,
+            None => return,
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("The current moment is beyond the Unix Epoch.")
+            .as_nanos();
+        let mut line = String::new();
+        let action = fmt.format_record(
+            &mut line,
+            &Record::builder()
+                .args(format_args!(
+                    "log_loki shutting down, delivered={} dropped={} pending={} uptime_secs={}",
+                    self.delivered,
+                    self.dropped + self.memory_budget.as_ref().map_or(0, |b| b.rejected()),
+                    self.dlq_lines,
+                    self.started.elapsed().as_secs()
+                ))
+                .level(Level::Info)
+                .target("log_loki")
+                .build(),
+        );
+        if action == Ok(FormatAction::Send) {
+            let mut lp = self.new_push(0, None, 1);
+            lp.streams[0].values.push(LokiValue {
+                ts: now,
+                line,
+                metadata: None,
+            });
+            lp.first = Some(now);
+            lp.id = self.next_batch_id;
+            self.next_batch_id += 1;
+            self.submit_logs(&mut lp, dlq);
+        }
+    }
+
+    // Count the lines of a batch as dropped and free up their share of the memory budget.
+    fn drop_batch(&mut self, lp: &LokiPush, reason: &str) {
+        self.dropped += lp.streams[0].values.len();
+        self.report(lp, Err(reason));
+        self.release(lp);
+        if !self.report_drops_to_loki {
+            return;
+        }
+        // while Loki is unreachable, the report would most likely fail too, after blocking the
+        // thread for as long as the request timeout, so it waits for the next successful push
+        if self.unreachable || self.circuit_open_until.is_some() {
+            let (batches, lines, last_reason) = &mut self.unreported_drops;
+            *batches += 1;
+            *lines += lp.streams[0].values.len();
+            reason.clone_into(last_reason);
+        } else {
+            self.report_drop(
+                lp.route,
+                format!(
+                    "level=warn message=\"log_loki dropped a batch\" lines={} reason={:?}",
+                    lp.streams[0].values.len(),
+                    reason
+                ),
+            );
+        }
+    }
+
+    // Report the batches dropped while Loki was unreachable, and the lines the memory budget
+    // rejected in the meantime, in a single line.
+    fn report_outage_drops(&mut self, route: usize) {
+        let rejected = self.memory_budget.as_ref().map_or(0, |b| b.rejected());
+        let (batches, lines, reason) = std::mem::take(&mut self.unreported_drops);
+        if batches == 0 && rejected == self.reported_rejections {
+            return;
+        }
+        self.report_drop(
+            route,
+            format!(
+                "level=warn message=\"log_loki dropped batches while Loki was unreachable\" batches={} lines={} over_memory_budget={} reason={:?}",
+                batches,
+                lines,
+                rejected - self.reported_rejections,
+                reason
+            ),
+        );
+        self.reported_rejections = rejected;
+    }
+
+    // Send a line about dropped logs to a stream of its own. As this is usually called when Loki is
+    // having trouble, failures are ignored rather than retried.
+    fn report_drop(&self, route: usize, line: String) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("The current moment is beyond the Unix Epoch.")
+            .as_nanos();
+
+        let internal = vec![(String::from("log_loki_internal"), String::from("dropped"))];
+        let mut report = self.new_push(route, Some(internal), 1);
+        report.streams[0].values.push(LokiValue {
+            ts: now,
+            line,
+            metadata: None,
+        });
+        let _ = self.post(&report);
+    }
+
+    // Tell the batch result hook, if any, what finally happened to a batch.
+    fn report(&self, lp: &LokiPush, result: Result<(), &str>) {
+        if let Some(hook) = &self.on_batch_result {
+            hook(lp.id, result);
+        }
+    }
+
+    // Free up the share of the memory budget held by the lines of a batch.
+    fn release(&self, lp: &LokiPush) {
+        if let Some(budget) = &self.memory_budget {
+            budget.release(
+                lp.streams[0]
+                    .values
+                    .iter()
+                    .map(|v| entry_cost(&v.line))
+                    .sum(),
+            );
+        }
+    }
+
     // Handle failure of batch and optionally retry a transistent failure.
     fn fail(
-        &self,
+        &mut self,
         lp: &mut LokiPush,
         dlq: &mut BinaryHeap<Reverse<FailedPush>>,
         emsg: &str,
         transistent: bool,
+        status: Option<u16>,
     ) {
+        self.consecutive_failures += 1;
+        self.unreachable = transistent;
+        if self.consecutive_failures > self.unhealthy_after {
+            self.status.healthy.store(false, Ordering::Relaxed);
+        }
+        if self.outage_since.is_none() {
+            self.outage_since = Some(Instant::now());
+        }
+
+        let mut lpc = lp.clone();
+        lpc.failures += 1;
+
+        // reset shared struct
+        lp.streams[0].values.clear();
+        lp.first = None;
+
         if self.failure_policy == FailurePolicy::Drop || !transistent {
-            eprintln!(
-                "(Loki) Failed to push batch of {} logs: {}; Dropping...",
-                lp.streams[0].values.len(),
+            self.diagnostics.report(format_args!(
+                "Failed to push batch of {} logs: {}; Dropping...",
+                lpc.streams[0].values.len(),
                 emsg
-            );
+            ));
+            self.drop_batch(&lpc, emsg);
             return;
         } else if let FailurePolicy::Retry(max_retries) = self.failure_policy.clone() {
-            if lp.failures > max_retries {
-                eprintln!("(Loki) Failed to push batch of {} logs: {}; Exceeded max retries of {}, dropping...", lp.streams[0].values.len(), emsg, max_retries);
+            if lpc.failures > max_retries + 1 {
+                self.diagnostics.report(format_args!(
+                    "Failed to push batch of {} logs: {}; Exceeded max retries of {}, dropping...",
+                    lpc.streams[0].values.len(),
+                    emsg,
+                    max_retries
+                ));
+                self.drop_batch(&lpc, emsg);
                 return;
             }
-            eprintln!(
-                "(Loki) Failed to push batch of {} logs: {}; Attempt {} of {}",
-                lp.streams[0].values.len(),
+            self.diagnostics.report(format_args!(
+                "Failed to push batch of {} logs: {}; Attempt {} of {}",
+                lpc.streams[0].values.len(),
                 emsg,
-                lp.failures + 1,
+                lpc.failures,
                 max_retries + 1
-            );
+            ));
+        } else if let FailurePolicy::RetryUntil(max_age) = self.failure_policy.clone() {
+            let first_failed = *lpc.first_failed.get_or_insert_with(Instant::now);
+            if first_failed.elapsed() > max_age {
+                self.diagnostics.report(format_args!("Failed to push batch of {} logs: {}; Exceeded max retry time of {:?}, dropping...", lpc.streams[0].values.len(), emsg, max_age));
+                self.drop_batch(&lpc, emsg);
+                return;
+            }
+            self.diagnostics.report(format_args!(
+                "Failed to push batch of {} logs: {}; Attempt {}",
+                lpc.streams[0].values.len(),
+                emsg,
+                lpc.failures
+            ));
         }
-        let mut lpc = lp.clone();
-        lpc.failures += 1;
 
-        // reset shared struct
-        lp.streams[0].values.clear();
-        lp.first = None;
+        let retry_at = match self.retry_scheduler.next_retry(lpc.failures, status) {
+            Some(at) => at,
+            None => {
+                self.diagnostics.report(format_args!(
+                    "Failed to push batch of {} logs: {}; Not rescheduled, dropping...",
+                    lpc.streams[0].values.len(),
+                    emsg
+                ));
+                self.drop_batch(&lpc, emsg);
+                return;
+            }
+        };
 
-        // calculate backoff
-        let retry_at: u128 = {
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("The current moment is beyond the Unix Epoch.")
-                .as_nanos()
-        } + ((1 << lpc.failures) * 1_000_000_000); // exp backoff of 2^x
+        self.requeue(lpc, dlq, retry_at);
+    }
 
-        dlq.push(Reverse(FailedPush {
+    // Add a batch to the dlq to be retried at the given time, or write it to the fallback file.
+    fn requeue(
+        &mut self,
+        lpc: LokiPush,
+        dlq: &mut BinaryHeap<Reverse<FailedPush>>,
+        retry_at: Instant,
+    ) {
+        if self.should_fall_back(dlq) {
+            self.write_fallback(&lpc);
+            self.report(&lpc, Err("written to the fallback file"));
+            self.release(&lpc);
+            return;
+        }
+
+        // convert the backoff to a timestamp comparable with the rest of the dlq
+        let retry_at: u128 = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("The current moment is beyond the Unix Epoch.")
+            .as_nanos()
+            + retry_at
+                .saturating_duration_since(Instant::now())
+                .as_nanos();
+
+        let failed = FailedPush {
             retry_at,
+            first: lpc.first.expect("Only non-empty batches are submitted."),
+            lines: lpc.streams[0].values.len(),
             push: Box::from(lpc),
-        }));
+            #[cfg(feature = "compress")]
+            packed: None,
+        };
+        #[cfg(feature = "compress")]
+        let failed = if self.compress_dlq {
+            failed.pack()
+        } else {
+            failed
+        };
+        self.dlq_lines += failed.lines;
+        dlq.push(Reverse(failed));
+    }
+
+    // Whether failed batches should be written to the fallback file instead of being retried.
+    fn should_fall_back(&self, dlq: &BinaryHeap<Reverse<FailedPush>>) -> bool {
+        match (&self.fallback_file, self.outage_since) {
+            (Some(_), Some(since)) => {
+                since.elapsed() >= self.fallback_after || dlq.len() >= self.fallback_max_batches
+            }
+            _ => false,
+        }
+    }
+
+    // Append the batch to the fallback file as JSON lines.
+    fn write_fallback(&mut self, lp: &LokiPush) {
+        let path = self
+            .fallback_file
+            .as_ref()
+            .expect("Only called when a fallback file is set.");
+
+        let mut out = Vec::new();
+        json_lines(lp, &mut out);
+
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut f| f.write_all(&out));
+        if let Err(e) = result {
+            self.diagnostics.report(format_args!(
+                "Failed to write batch of {} logs to {}: {}; Dropping...",
+                lp.streams[0].values.len(),
+                path.display(),
+                e
+            ));
+            self.dropped += lp.streams[0].values.len();
+        }
+    }
+
+    // Write every batch in the dlq to a file, in the same format as the fallback file, and remove
+    // them from the dlq. If writing fails, the dlq is left as it was.
+    fn export_dlq(
+        &mut self,
+        path: &Path,
+        dlq: &mut BinaryHeap<Reverse<FailedPush>>,
+    ) -> std::io::Result<usize> {
+        let failed: Vec<Box<LokiPush>> = dlq.iter().map(|v| v.0.clone().into_push()).collect();
+
+        let mut out = Vec::new();
+        for lp in &failed {
+            json_lines(lp, &mut out);
+        }
+        // an existing file, e.g. an earlier export that wasn't imported yet, is never overwritten
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .and_then(|mut f| f.write_all(&out))?;
+
+        dlq.clear();
+        self.dlq_lines = 0;
+        let mut exported = 0;
+        for lp in &failed {
+            exported += lp.streams[0].values.len();
+            self.report(lp, Err("exported"));
+            self.release(lp);
+        }
+        Ok(exported)
     }
 
     // Retry a failed item if there is one to retry. Returns true if it did
     // something, false otherwise.
-    fn retry_failed(&self, dlq: &mut BinaryHeap<Reverse<FailedPush>>) -> bool {
+    fn retry_failed(&mut self, dlq: &mut BinaryHeap<Reverse<FailedPush>>) -> bool {
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("The current moment is beyond the Unix Epoch.")
@@ -271,32 +1275,236 @@ impl LokiTask {
             return false;
         }
 
-        let mut lp = dlq
-            .pop()
-            .expect("We checked if this had a value in the peek() above")
-            .0
-            .push;
+        if let (Some(spacing), Some(last)) = (self.retry_spacing, self.last_retry) {
+            if last.elapsed() < spacing {
+                return false;
+            }
+        }
+        self.last_retry = Some(Instant::now());
+
+        let failed = match self.retry_order {
+            RetryOrder::OldestFirst => {
+                dlq.pop()
+                    .expect("We checked if this had a value in the peek() above")
+                    .0
+            }
+            // the newest of the batches that are due, which the heap can't pick on its own
+            RetryOrder::NewestFirst => {
+                let mut failed = std::mem::take(dlq).into_vec();
+                let newest = failed
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, v)| v.0.retry_at <= now)
+                    .max_by_key(|(_, v)| v.0.first)
+                    .map(|(i, _)| i)
+                    .expect("We checked if the first item was due in the peek() above");
+                let newest = failed.swap_remove(newest).0;
+                *dlq = BinaryHeap::from(failed);
+                newest
+            }
+        };
+        self.dlq_lines -= failed.lines;
+        let mut lp = failed.into_push();
         self.submit_logs(&mut lp, dlq);
         true
     }
 
     // Retry everything during a forced flush.
-    fn retry_all_failed(&self, dlq: &mut BinaryHeap<Reverse<FailedPush>>) {
+    fn retry_all_failed(&mut self, dlq: &mut BinaryHeap<Reverse<FailedPush>>) {
         let mut t: BinaryHeap<Reverse<FailedPush>> = BinaryHeap::new();
 
+        // whatever fails again is counted again as it is added to t
+        self.dlq_lines = 0;
         for v in dlq.drain() {
-            self.submit_logs(&mut v.0.push.clone(), &mut t);
+            self.submit_logs(&mut v.0.into_push(), &mut t);
         }
 
         *dlq = t;
     }
 }
 
+// Labels added by label scopes, sorted by name
+pub type ScopeLabels = Vec<(String, String)>;
+
+// Key value pairs sent along with a single line as Loki's structured metadata
+pub type StructuredMetadata = Vec<(String, String)>;
+
+// The contents of a LokiTaskMsg::Log
+pub type LogEntry = (
+    u128,
+    Level,
+    String,
+    Option<ScopeLabels>,
+    Option<StructuredMetadata>,
+);
+
 // LokiTaskMsg is used by the main thread to send messages to the LokiTask
 #[derive(Clone, Debug)]
 pub enum LokiTaskMsg {
-    Log(u128, String),
+    Log(
+        u128,
+        Level,
+        String,
+        Option<ScopeLabels>,
+        Option<StructuredMetadata>,
+    ),
+    LogBatch(Vec<LogEntry>),
     Flush,
+    FlushAsync(Arc<FlushSignal>),
+    RequestFlush,
+    Drain(Sender<Vec<(u128, String)>>),
+    Dump(Sender<Vec<String>>),
+    ExportDlq(PathBuf, Sender<std::io::Result<usize>>),
+    Shutdown(Sender<()>),
+    Quiesce(Sender<()>),
+    SelfTest(Sender<Result<(), LokiError>>),
+    SetMaxLogs(usize),
+    SetMaxLifetime(Duration),
+}
+
+// FlushSignal is completed by the LokiTask once a flush requested by `Loki::flush_async` is done
+#[derive(Default, Debug)]
+pub struct FlushSignal {
+    state: Mutex<(bool, Option<Waker>)>,
+}
+
+impl FlushSignal {
+    fn complete(&self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.0 = true;
+        if let Some(waker) = state.1.take() {
+            waker.wake();
+        }
+    }
+
+    pub fn poll_complete(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.0 {
+            Poll::Ready(())
+        } else {
+            state.1 = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+// The lines a thread buffered for LokiBuilder::micro_batch, and when the first of them was buffered
+#[derive(Debug, Default)]
+pub struct MicroBatch {
+    pub lines: Vec<LogEntry>,
+    started: Option<Instant>,
+}
+
+// The micro batches of every thread that logged, so the background thread can collect them
+pub type MicroBatches = Arc<Mutex<Vec<Arc<Mutex<MicroBatch>>>>>;
+
+impl MicroBatch {
+    pub fn push(&mut self, entry: LogEntry) {
+        self.started.get_or_insert_with(Instant::now);
+        self.lines.push(entry);
+    }
+
+    pub fn is_due(&self, max_delay: Duration) -> bool {
+        self.started.is_some_and(|s| s.elapsed() >= max_delay)
+    }
+
+    pub fn take(&mut self) -> Vec<LogEntry> {
+        self.started = None;
+        std::mem::take(&mut self.lines)
+    }
+}
+
+// TaskStatus is the state of the LokiTask that the logger reads: whether pushes are succeeding, and
+// the batch limits currently in effect, which may have been changed at runtime
+#[derive(Debug)]
+pub struct TaskStatus {
+    pub healthy: AtomicBool,
+    pub max_log_lines: AtomicUsize,
+    max_log_lifetime_nanos: AtomicU64,
+}
+
+impl TaskStatus {
+    pub fn new(max_log_lines: usize, max_log_lifetime: Duration) -> TaskStatus {
+        let status = TaskStatus {
+            healthy: AtomicBool::new(true),
+            max_log_lines: AtomicUsize::new(max_log_lines),
+            max_log_lifetime_nanos: AtomicU64::new(0),
+        };
+        status.set_max_log_lifetime(max_log_lifetime);
+        status
+    }
+
+    pub fn max_log_lifetime(&self) -> Duration {
+        Duration::from_nanos(self.max_log_lifetime_nanos.load(Ordering::Relaxed))
+    }
+
+    fn set_max_log_lifetime(&self, lifetime: Duration) {
+        let nanos = u64::try_from(lifetime.as_nanos()).unwrap_or(u64::MAX);
+        self.max_log_lifetime_nanos.store(nanos, Ordering::Relaxed);
+    }
+}
+
+// MemoryBudget tracks the estimated memory held by undelivered lines across both threads
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: usize,
+    used: AtomicUsize,
+    rejected: AtomicUsize,
+    exhausted: AtomicBool,
+    diagnostics: Arc<Diagnostics>,
+}
+
+// Estimated memory used by a line while it waits to be delivered
+fn entry_cost(line: &str) -> usize {
+    line.len() + std::mem::size_of::<LokiValue>()
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize, diagnostics: Arc<Diagnostics>) -> MemoryBudget {
+        MemoryBudget {
+            limit,
+            used: AtomicUsize::new(0),
+            rejected: AtomicUsize::new(0),
+            exhausted: AtomicBool::new(false),
+            diagnostics,
+        }
+    }
+
+    // Reserves room for the line, returning false if the line has to be dropped
+    pub fn try_reserve(&self, line: &str) -> bool {
+        let cost = entry_cost(line);
+        let reserved = self
+            .used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                Some(used + cost).filter(|total| *total <= self.limit)
+            })
+            .is_ok();
+
+        if reserved {
+            self.exhausted.store(false, Ordering::Relaxed);
+        } else {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            if !self.exhausted.swap(true, Ordering::Relaxed) {
+                self.diagnostics.report(format_args!(
+                    "Memory budget of {} bytes exhausted, dropping new logs...",
+                    self.limit
+                ));
+            }
+        }
+        reserved
+    }
+
+    fn release(&self, bytes: usize) {
+        let _ = self
+            .used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                Some(used.saturating_sub(bytes))
+            });
+    }
+
+    fn rejected(&self) -> usize {
+        self.rejected.load(Ordering::Relaxed)
+    }
 }
 
 #[derive(Serialize, Clone)]
@@ -304,20 +1512,219 @@ struct LokiPush {
     streams: [LokiStream; 1],
     #[serde(skip_serializing)]
     first: Option<u128>,
+    // the timestamp the last line was logged with, before it was made unique
+    #[serde(skip_serializing)]
+    last_logged: u128,
+    // assigned when the first line is added, and kept across retries
+    #[serde(skip_serializing)]
+    id: u64,
     #[serde(skip_serializing)]
     failures: usize,
+    // when the batch failed for the first time
+    #[serde(skip_serializing)]
+    first_failed: Option<Instant>,
+    // index of the request this push is sent with
+    #[serde(skip_serializing)]
+    route: usize,
+    // labels from a label scope, which are also part of the stream labels
+    #[serde(skip_serializing)]
+    scope: Option<ScopeLabels>,
 }
 
-#[derive(Serialize, Clone)]
+#[derive(Clone)]
 struct LokiStream {
     stream: HashMap<String, String>,
-    values: Vec<[String; 2]>,
+    // the order to serialize the labels in, if it is preserved
+    order: Option<Arc<[String]>>,
+    values: Vec<LokiValue>,
+    shape: ValueShape,
+}
+
+impl Serialize for LokiStream {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("LokiStream", 2)?;
+        match &self.order {
+            Some(order) => state.serialize_field("stream", &OrderedLabels(&self.stream, order))?,
+            None => state.serialize_field("stream", &self.stream)?,
+        }
+        state.serialize_field("values", &ShapedValues(&self.values, self.shape))?;
+        state.end()
+    }
+}
+
+#[derive(Clone)]
+struct LokiValue {
+    ts: u128,
+    line: String,
+    metadata: Option<StructuredMetadata>,
+}
+
+// Append the lines of a batch as JSON lines with the `labels`, `ts`, `line`, and optionally
+// `metadata` fields
+fn json_lines(lp: &LokiPush, out: &mut Vec<u8>) {
+    for v in &lp.streams[0].values {
+        let mut entry =
+            json!({"labels": lp.streams[0].stream, "ts": v.ts.to_string(), "line": v.line});
+        if let Some(metadata) = &v.metadata {
+            entry["metadata"] = json!(metadata_map(metadata));
+        }
+        out.extend(entry.to_string().into_bytes());
+        out.push(b'\n');
+    }
+}
+
+// Structured metadata is sent as a JSON object. Later pairs win over earlier ones with the same key.
+fn metadata_map(metadata: &StructuredMetadata) -> HashMap<&str, &str> {
+    metadata
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect()
+}
+
+// Serializes stream labels in the given order, followed by any other labels sorted by name
+struct OrderedLabels<'a>(&'a HashMap<String, String>, &'a [String]);
+
+impl<'a> Serialize for OrderedLabels<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut rest: Vec<(&String, &String)> =
+            self.0.iter().filter(|(k, _)| !self.1.contains(k)).collect();
+        rest.sort();
+
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for k in self.1 {
+            if let Some(v) = self.0.get(k) {
+                map.serialize_entry(k, v)?;
+            }
+        }
+        for (k, v) in rest {
+            map.serialize_entry(k, v)?;
+        }
+        map.end()
+    }
+}
+
+// Serializes log entries in the shape expected by the server
+struct ShapedValues<'a>(&'a [LokiValue], ValueShape);
+
+impl<'a> Serialize for ShapedValues<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.0.len()))?;
+        for v in self.0 {
+            let ts = v.ts.to_string();
+            match (self.1, &v.metadata) {
+                (ValueShape::TimestampLine, None) => seq.serialize_element(&(ts, &v.line))?,
+                (ValueShape::TimestampLine, Some(m)) => {
+                    seq.serialize_element(&(ts, &v.line, metadata_map(m)))?
+                }
+                (ValueShape::LineTimestamp, None) => seq.serialize_element(&(&v.line, ts))?,
+                (ValueShape::LineTimestamp, Some(m)) => {
+                    seq.serialize_element(&(&v.line, ts, metadata_map(m)))?
+                }
+                (ValueShape::Object, None) => {
+                    seq.serialize_element(&json!({"ts": ts, "line": v.line}))?
+                }
+                (ValueShape::Object, Some(m)) => seq.serialize_element(
+                    &json!({"ts": ts, "line": v.line, "metadata": metadata_map(m)}),
+                )?,
+            }
+        }
+        seq.end()
+    }
 }
 
 #[derive(Derivative)]
 #[derivative(PartialEq, Eq, PartialOrd, Ord, Clone)]
 struct FailedPush {
+    // the dlq is ordered by when the items are due
     retry_at: u128,
+    // the timestamp of the first line, for RetryOrder::NewestFirst
+    #[derivative(PartialEq = "ignore", PartialOrd = "ignore", Ord = "ignore")]
+    first: u128,
+    // number of lines in the push, even while they are compressed
+    #[derivative(PartialEq = "ignore", PartialOrd = "ignore", Ord = "ignore")]
+    lines: usize,
     #[derivative(PartialEq = "ignore", PartialOrd = "ignore", Ord = "ignore")]
     push: Box<LokiPush>,
+    // the values of the push, compressed, when the dlq is compressed
+    #[cfg(feature = "compress")]
+    #[derivative(PartialEq = "ignore", PartialOrd = "ignore", Ord = "ignore")]
+    packed: Option<Vec<u8>>,
+}
+
+impl FailedPush {
+    // Move the values of the push into a compressed buffer. Each value is stored as its
+    // timestamp, its line, and the number of structured metadata pairs followed by the pairs.
+    #[cfg(feature = "compress")]
+    fn pack(mut self) -> FailedPush {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+        for v in &self.push.streams[0].values {
+            // writing to a Vec can't fail
+            let _ = encoder.write_all(&v.ts.to_le_bytes());
+            write_packed_str(&mut encoder, &v.line);
+            let metadata = v.metadata.as_deref().unwrap_or_default();
+            let _ = encoder.write_all(&(metadata.len() as u64).to_le_bytes());
+            for (k, v) in metadata {
+                write_packed_str(&mut encoder, k);
+                write_packed_str(&mut encoder, v);
+            }
+        }
+        if let Ok(packed) = encoder.finish() {
+            self.push.streams[0].values = Vec::new();
+            self.packed = Some(packed);
+        }
+        self
+    }
+
+    // Take the push out of the dlq entry, decompressing its values if needed
+    fn into_push(self) -> Box<LokiPush> {
+        #[cfg(feature = "compress")]
+        if let Some(packed) = self.packed {
+            let mut raw = Vec::new();
+            ZlibDecoder::new(&packed[..])
+                .read_to_end(&mut raw)
+                .expect("The dlq entry was compressed by us.");
+
+            let mut push = self.push;
+            push.streams[0].values.reserve(self.lines);
+            let mut rest = &raw[..];
+            while !rest.is_empty() {
+                let ts = u128::from_le_bytes(read_packed(&mut rest, 16).try_into().unwrap());
+                let line = read_packed_str(&mut rest);
+                let pairs = u64::from_le_bytes(read_packed(&mut rest, 8).try_into().unwrap());
+                let metadata = (pairs > 0).then(|| {
+                    (0..pairs)
+                        .map(|_| (read_packed_str(&mut rest), read_packed_str(&mut rest)))
+                        .collect()
+                });
+                push.streams[0]
+                    .values
+                    .push(LokiValue { ts, line, metadata });
+            }
+            return push;
+        }
+
+        self.push
+    }
+}
+
+// Write a length prefixed string to a packed dlq entry
+#[cfg(feature = "compress")]
+fn write_packed_str(w: &mut impl Write, s: &str) {
+    let _ = w.write_all(&(s.len() as u64).to_le_bytes());
+    let _ = w.write_all(s.as_bytes());
+}
+
+// Take the next `len` bytes of a packed dlq entry
+#[cfg(feature = "compress")]
+fn read_packed<'a>(rest: &mut &'a [u8], len: usize) -> &'a [u8] {
+    let (head, tail) = rest.split_at(len);
+    *rest = tail;
+    head
+}
+
+// Take the next length prefixed string of a packed dlq entry
+#[cfg(feature = "compress")]
+fn read_packed_str(rest: &mut &[u8]) -> String {
+    let len = u64::from_le_bytes(read_packed(rest, 8).try_into().unwrap()) as usize;
+    String::from_utf8_lossy(read_packed(rest, len)).into_owned()
 }
@@ -0,0 +1,192 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+//! Utilities for end-to-end testing of code that logs to Loki.
+
+use flate2::read::{GzDecoder, ZlibDecoder};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::spawn;
+use url::Url;
+
+/// A push request received by the `MockLokiServer`.
+#[derive(Debug, Clone)]
+pub struct ReceivedPush {
+    /// The request headers. Header names are lowercased.
+    pub headers: HashMap<String, String>,
+    /// The decompressed request body, or `Null` if it wasn't valid JSON.
+    pub body: serde_json::Value,
+    /// The status code the push was answered with.
+    pub status: u16,
+    /// The connection the push arrived on, numbered from 0 in the order the connections were
+    /// accepted. Pushes on the same connection mean that the connection was reused.
+    pub connection: usize,
+}
+
+impl ReceivedPush {
+    /// Returns the log lines of every stream in the push, in the order they were sent.
+    /// This assumes the default `ValueShape::TimestampLine`.
+    pub fn lines(&self) -> Vec<String> {
+        self.body["streams"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|s| s["values"].as_array())
+            .flatten()
+            .filter_map(|v| v[1].as_str().map(|l| l.to_owned()))
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct MockState {
+    pushes: Mutex<Vec<ReceivedPush>>,
+    responses: Mutex<VecDeque<u16>>,
+    stopped: AtomicBool,
+}
+
+/// `MockLokiServer` is a minimal HTTP server on localhost that accepts pushes like Loki would and
+/// records them for later assertions. Gzip and deflate compressed pushes are decoded. By default
+/// every push is answered with 204, but other status codes can be queued with
+/// `MockLokiServer::respond_with` to exercise the retry logic. The server stops when it is dropped.
+pub struct MockLokiServer {
+    addr: SocketAddr,
+    state: Arc<MockState>,
+}
+
+impl MockLokiServer {
+    /// Binds the server to a random port on localhost and starts accepting connections.
+    pub fn start() -> io::Result<MockLokiServer> {
+        MockLokiServer::bind("127.0.0.1:0")
+    }
+
+    /// Binds the server to the given address and starts accepting connections, e.g. `[::1]:0` to
+    /// test IPv6 endpoints.
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<MockLokiServer> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(MockState::default());
+        let state2 = Arc::clone(&state);
+
+        spawn(move || {
+            for (connection, conn) in listener.incoming().enumerate() {
+                if state2.stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(conn) = conn {
+                    let state = Arc::clone(&state2);
+                    spawn(move || {
+                        // Errors just close the connection, just like a real server would
+                        let _ = serve(conn, connection, &state);
+                    });
+                }
+            }
+        });
+
+        Ok(MockLokiServer { addr, state })
+    }
+
+    /// Returns the push endpoint of the server, suitable for `LokiBuilder::new`.
+    pub fn endpoint(&self) -> Url {
+        Url::parse(&format!("http://{}/loki/api/v1/push", self.addr))
+            .expect("The mock server address should be a valid URL")
+    }
+
+    /// Queues a status code to answer the next push with. Queued codes are used in the order they
+    /// were added, after which pushes are answered with 204 again.
+    pub fn respond_with(&self, status: u16) {
+        self.state.responses.lock().unwrap().push_back(status);
+    }
+
+    /// Returns the pushes received so far, including the ones that were answered with an error.
+    pub fn pushes(&self) -> Vec<ReceivedPush> {
+        self.state.pushes.lock().unwrap().clone()
+    }
+
+    /// Returns the log lines of all pushes received so far that were answered with a success code.
+    pub fn lines(&self) -> Vec<String> {
+        self.pushes()
+            .iter()
+            .filter(|p| (200..300).contains(&p.status))
+            .flat_map(|p| p.lines())
+            .collect()
+    }
+}
+
+impl Drop for MockLokiServer {
+    fn drop(&mut self) {
+        self.state.stopped.store(true, Ordering::SeqCst);
+        // wake up the accept loop so it can notice that it was stopped
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+// Handles requests on a single keep-alive connection until the client closes it
+fn serve(conn: TcpStream, connection: usize, state: &MockState) -> io::Result<()> {
+    let mut writer = conn.try_clone()?;
+    let mut reader = BufReader::new(conn);
+
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line)? == 0 || state.stopped.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let mut headers = HashMap::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((k, v)) = line.split_once(':') {
+                headers.insert(k.trim().to_lowercase(), v.trim().to_owned());
+            }
+        }
+
+        let len = headers
+            .get("content-length")
+            .and_then(|l| l.parse().ok())
+            .unwrap_or(0);
+        let mut raw = vec![0; len];
+        reader.read_exact(&mut raw)?;
+
+        let raw = match headers.get("content-encoding").map(|e| e.as_str()) {
+            Some("gzip") => {
+                let mut decoded = Vec::new();
+                GzDecoder::new(&raw[..]).read_to_end(&mut decoded)?;
+                decoded
+            }
+            Some("deflate") => {
+                let mut decoded = Vec::new();
+                ZlibDecoder::new(&raw[..]).read_to_end(&mut decoded)?;
+                decoded
+            }
+            _ => raw,
+        };
+
+        let status = state.responses.lock().unwrap().pop_front().unwrap_or(204);
+        state.pushes.lock().unwrap().push(ReceivedPush {
+            headers,
+            body: serde_json::from_slice(&raw).unwrap_or(serde_json::Value::Null),
+            status,
+            connection,
+        });
+
+        write!(
+            writer,
+            "HTTP/1.1 {} Mock\r\nContent-Length: 0\r\n\r\n",
+            status
+        )?;
+        writer.flush()?;
+    }
+}
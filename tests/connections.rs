@@ -0,0 +1,35 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+#![cfg(feature = "test-util")]
+
+use log::{Level, Log, Record};
+use log_loki::test_util::MockLokiServer;
+use log_loki::LokiBuilder;
+use std::collections::HashMap;
+
+#[test]
+fn pushes_reuse_the_connection() {
+    let server = MockLokiServer::start().unwrap();
+    let labels = HashMap::from([(String::from("app"), String::from("test"))]);
+    let loki = LokiBuilder::new(server.endpoint(), labels).build();
+
+    for i in 0..5 {
+        loki.log(
+            &Record::builder()
+                .args(format_args!("push {}", i))
+                .level(Level::Info)
+                .build(),
+        );
+        loki.flush();
+    }
+
+    let pushes = server.pushes();
+    assert_eq!(pushes.len(), 5);
+    assert!(pushes.iter().all(|p| p.connection == pushes[0].connection));
+}
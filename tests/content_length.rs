@@ -0,0 +1,74 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+#![cfg(all(feature = "test-util", feature = "compress"))]
+
+use log::{Level, Log, Record};
+use log_loki::test_util::{MockLokiServer, ReceivedPush};
+use log_loki::{CompressionPolicy, LokiBuilder};
+use std::collections::HashMap;
+
+// Sends two batches over the same connection, so that a wrong length would also garble the second
+fn push_twice(policy: CompressionPolicy) -> Vec<ReceivedPush> {
+    let server = MockLokiServer::start().unwrap();
+    let labels = HashMap::from([(String::from("app"), String::from("test"))]);
+    let loki = LokiBuilder::new(server.endpoint(), labels)
+        .compression(policy)
+        .build();
+
+    for batch in 0..2 {
+        for i in 0..50 {
+            loki.log(
+                &Record::builder()
+                    .args(format_args!(
+                        "batch {} line {} with \"quotes\" and ünicode",
+                        batch, i
+                    ))
+                    .level(Level::Info)
+                    .build(),
+            );
+        }
+        loki.flush();
+    }
+
+    let pushes = server.pushes();
+    assert_eq!(pushes.len(), 2);
+    assert_eq!(pushes[0].connection, pushes[1].connection);
+    for push in &pushes {
+        assert!(!push.headers.contains_key("transfer-encoding"));
+        assert!(push.headers.contains_key("content-length"));
+        assert_eq!(push.lines().len(), 50);
+    }
+    pushes
+}
+
+fn content_length(push: &ReceivedPush) -> usize {
+    push.headers["content-length"].parse().unwrap()
+}
+
+#[test]
+fn content_length_matches_uncompressed_body() {
+    for push in push_twice(CompressionPolicy::Never) {
+        assert!(!push.headers.contains_key("content-encoding"));
+        // the length of compact JSON doesn't depend on the order of the keys
+        let body = serde_json::to_vec(&push.body).unwrap();
+        assert_eq!(content_length(&push), body.len());
+    }
+}
+
+#[test]
+fn content_length_matches_compressed_body() {
+    for push in push_twice(CompressionPolicy::Always) {
+        assert_eq!(push.headers["content-encoding"], "gzip");
+        // the mock server reads exactly Content-Length bytes before decompressing them, so a
+        // body that decoded to the full batch had the right length
+        let body = serde_json::to_vec(&push.body).unwrap();
+        assert!(content_length(&push) > 0);
+        assert!(content_length(&push) < body.len());
+    }
+}
@@ -0,0 +1,73 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+#![cfg(feature = "test-util")]
+
+use log::{Level, Log, Record};
+use log_loki::test_util::{MockLokiServer, ReceivedPush};
+use log_loki::{FailurePolicy, Loki, LokiBuilder};
+use std::collections::HashMap;
+
+fn log_line(loki: &Loki, line: &str) {
+    loki.log(
+        &Record::builder()
+            .args(format_args!("{}", line))
+            .level(Level::Info)
+            .build(),
+    );
+    loki.flush();
+}
+
+fn is_drop_report(push: &ReceivedPush) -> bool {
+    push.body["streams"][0]["stream"]["log_loki_internal"] == "dropped"
+}
+
+#[test]
+fn drops_during_outage_are_reported_after_recovery() {
+    let server = MockLokiServer::start().unwrap();
+    let labels = HashMap::from([(String::from("app"), String::from("test"))]);
+    let loki = LokiBuilder::new(server.endpoint(), labels)
+        .failure_policy(FailurePolicy::Drop)
+        .report_drops_to_loki(true)
+        .build();
+
+    for i in 0..3 {
+        server.respond_with(503);
+        log_line(&loki, &format!("lost {}", i));
+    }
+    assert!(!server.pushes().iter().any(is_drop_report));
+
+    log_line(&loki, "recovered");
+    let reports: Vec<String> = server
+        .pushes()
+        .iter()
+        .filter(|p| is_drop_report(p))
+        .flat_map(|p| p.lines())
+        .collect();
+    assert_eq!(reports.len(), 1);
+    assert!(reports[0].contains("batches=3 lines=3"), "{}", reports[0]);
+    assert!(server.lines().iter().any(|l| l.contains("recovered")));
+}
+
+#[test]
+fn rejected_batches_are_reported_right_away() {
+    let server = MockLokiServer::start().unwrap();
+    let labels = HashMap::from([(String::from("app"), String::from("test"))]);
+    let loki = LokiBuilder::new(server.endpoint(), labels)
+        .failure_policy(FailurePolicy::Drop)
+        .report_drops_to_loki(true)
+        .build();
+
+    server.respond_with(400);
+    log_line(&loki, "rejected");
+
+    let pushes = server.pushes();
+    assert_eq!(pushes.len(), 2);
+    assert!(is_drop_report(&pushes[1]));
+    assert!(pushes[1].lines()[0].contains("lines=1"));
+}
@@ -0,0 +1,79 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+#![cfg(feature = "test-util")]
+
+use log::{Level, Log, Record};
+use log_loki::test_util::MockLokiServer;
+use log_loki::LokiBuilder;
+use std::collections::HashMap;
+
+// Binds a mock server to the IPv6 loopback address, or returns None if the host has no IPv6
+fn ipv6_server() -> Option<MockLokiServer> {
+    match MockLokiServer::bind("[::1]:0") {
+        Ok(server) => Some(server),
+        Err(e) => {
+            eprintln!("Skipping, IPv6 loopback is unavailable: {}", e);
+            None
+        }
+    }
+}
+
+fn log_line(loki: &impl Log, level: Level, line: &str) {
+    loki.log(
+        &Record::builder()
+            .args(format_args!("{}", line))
+            .level(level)
+            .build(),
+    );
+}
+
+#[test]
+fn pushes_to_bracketed_ipv6_endpoint() {
+    let server = match ipv6_server() {
+        Some(server) => server,
+        None => return,
+    };
+    let endpoint = server.endpoint();
+    assert!(endpoint.as_str().starts_with("http://[::1]:"));
+
+    let labels = HashMap::from([(String::from("app"), String::from("test"))]);
+    let loki = LokiBuilder::new(endpoint.clone(), labels).build();
+    log_line(&loki, Level::Info, "over ipv6");
+    loki.flush();
+
+    let pushes = server.pushes();
+    assert_eq!(pushes.len(), 1);
+    assert_eq!(pushes[0].status, 204);
+    assert_eq!(
+        pushes[0].headers["host"],
+        format!("[::1]:{}", endpoint.port().unwrap())
+    );
+    assert!(pushes[0].lines()[0].contains("over ipv6"));
+}
+
+#[test]
+fn routes_levels_to_bracketed_ipv6_endpoint() {
+    let (default, errors) = match (ipv6_server(), ipv6_server()) {
+        (Some(default), Some(errors)) => (default, errors),
+        _ => return,
+    };
+
+    let labels = HashMap::from([(String::from("app"), String::from("test"))]);
+    let loki = LokiBuilder::new(default.endpoint(), labels)
+        .route_level(Level::Error, errors.endpoint())
+        .build();
+    log_line(&loki, Level::Info, "routine");
+    log_line(&loki, Level::Error, "failure");
+    loki.flush();
+
+    assert_eq!(default.lines().len(), 1);
+    assert!(default.lines()[0].contains("routine"));
+    assert_eq!(errors.lines().len(), 1);
+    assert!(errors.lines()[0].contains("failure"));
+}
@@ -0,0 +1,77 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+#![cfg(feature = "logfmt")]
+
+use log::{Level, Record};
+use log_loki::{LogfmtAutoFields, LogfmtFormatter, LokiFormatter};
+
+fn formatter() -> LogfmtFormatter {
+    LogfmtFormatter::new(
+        LogfmtAutoFields::LEVEL
+            | LogfmtAutoFields::MESSAGE
+            | LogfmtAutoFields::MODULE_PATH
+            | LogfmtAutoFields::CRATE
+            | LogfmtAutoFields::FILE,
+        false,
+    )
+}
+
+fn format(rec: &Record) -> String {
+    let mut line = String::new();
+    formatter().write_record(&mut line, rec).unwrap();
+    line
+}
+
+#[test]
+fn static_fields_appear_on_every_record() {
+    for (level, message) in [(Level::Info, "first"), (Level::Warn, "second")] {
+        let line = format(
+            &Record::builder()
+                .args(format_args!("{}", message))
+                .level(level)
+                .module_path_static(Some("app::db"))
+                .file_static(Some("src/db.rs"))
+                .build(),
+        );
+        assert!(line.contains(&format!("message={}", message)), "{}", line);
+        assert!(line.contains("module=app::db"), "{}", line);
+        assert!(line.contains("crate=app"), "{}", line);
+        assert!(line.contains("file=src/db.rs"), "{}", line);
+    }
+}
+
+#[test]
+fn non_static_fields_are_used_too() {
+    let module = String::from("app::cache");
+    let file = String::from("src/cache.rs");
+    let line = format(
+        &Record::builder()
+            .args(format_args!("miss"))
+            .level(Level::Debug)
+            .module_path(Some(&module))
+            .file(Some(&file))
+            .build(),
+    );
+    assert!(line.contains("module=app::cache"), "{}", line);
+    assert!(line.contains("crate=app"), "{}", line);
+    assert!(line.contains("file=src/cache.rs"), "{}", line);
+}
+
+#[test]
+fn missing_fields_are_left_out() {
+    let line = format(
+        &Record::builder()
+            .args(format_args!("bare"))
+            .level(Level::Info)
+            .build(),
+    );
+    assert!(!line.contains("module="), "{}", line);
+    assert!(!line.contains("crate="), "{}", line);
+    assert!(!line.contains("file="), "{}", line);
+}
@@ -0,0 +1,50 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+#![cfg(feature = "test-util")]
+
+use log::{Level, Log, Record};
+use log_loki::test_util::MockLokiServer;
+use log_loki::{FailurePolicy, LokiBuilder, RetryScheduler};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Retries quickly, to keep the test short
+struct QuickRetry;
+
+impl RetryScheduler for QuickRetry {
+    fn next_retry(&self, _attempt: usize, _status: Option<u16>) -> Option<Instant> {
+        Some(Instant::now() + Duration::from_millis(50))
+    }
+}
+
+#[test]
+fn timed_out_waits_dont_block_later_ones() {
+    let server = MockLokiServer::start().unwrap();
+    let labels = HashMap::from([(String::from("app"), String::from("test"))]);
+    let loki = LokiBuilder::new(server.endpoint(), labels)
+        .failure_policy(FailurePolicy::Retry(10))
+        .retry_scheduler(Box::new(QuickRetry))
+        .build();
+
+    for _ in 0..3 {
+        server.respond_with(503);
+    }
+    loki.log(
+        &Record::builder()
+            .args(format_args!("retried"))
+            .level(Level::Info)
+            .build(),
+    );
+    // the outage outlasts these, so they give up while their replies are still queued
+    for _ in 0..3 {
+        assert!(!loki.wait_quiescent(Duration::from_millis(1)));
+    }
+    assert!(loki.wait_quiescent(Duration::from_secs(10)));
+    assert_eq!(server.lines().len(), 1);
+}
@@ -0,0 +1,54 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+#![cfg(feature = "test-util")]
+
+use log::Log;
+use log_loki::test_util::MockLokiServer;
+use log_loki::LokiBuilder;
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[test]
+fn config_summary_reports_runtime_limits() {
+    let server = MockLokiServer::start().unwrap();
+    let labels = HashMap::from([(String::from("app"), String::from("test"))]);
+    let loki = LokiBuilder::new(server.endpoint(), labels)
+        .max_logs(500)
+        .build();
+    assert_eq!(loki.config_summary().max_log_lines, 500);
+
+    let handle = loki.handle();
+    handle.set_max_logs(50);
+    handle.set_max_lifetime(Duration::from_secs(5));
+    // the flush is handled after the changes, so they are in effect once it returns
+    loki.flush();
+
+    let summary = loki.config_summary();
+    assert_eq!(summary.max_log_lines, 50);
+    assert_eq!(summary.max_log_lifetime, Duration::from_secs(5));
+}
+
+#[test]
+fn runtime_limit_is_clamped_to_adaptive_bounds() {
+    let server = MockLokiServer::start().unwrap();
+    let labels = HashMap::from([(String::from("app"), String::from("test"))]);
+    let loki = LokiBuilder::new(server.endpoint(), labels)
+        .max_logs(50)
+        .adaptive_batch_sizing(Duration::from_secs(1), 10, 100)
+        .build();
+
+    let handle = loki.handle();
+    handle.set_max_logs(1000);
+    loki.flush();
+    assert_eq!(loki.config_summary().max_log_lines, 100);
+
+    handle.set_max_logs(1);
+    loki.flush();
+    assert_eq!(loki.config_summary().max_log_lines, 10);
+}
@@ -0,0 +1,75 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+#![cfg(feature = "test-util")]
+
+use log::{Level, Log, Record};
+use log_loki::test_util::MockLokiServer;
+use log_loki::{FailurePolicy, Loki, LokiBuilder};
+use std::collections::HashMap;
+use std::time::Duration;
+
+fn log_line(loki: &Loki, line: &str) {
+    loki.log(
+        &Record::builder()
+            .args(format_args!("{}", line))
+            .level(Level::Info)
+            .build(),
+    );
+}
+
+#[test]
+fn lines_after_shutdown_get_the_phase_label() {
+    let server = MockLokiServer::start().unwrap();
+    let labels = HashMap::from([(String::from("app"), String::from("test"))]);
+    let loki = LokiBuilder::new(server.endpoint(), labels)
+        .shutdown_phase_label(true)
+        .build();
+
+    log_line(&loki, "running");
+    assert!(loki.shutdown(Duration::from_secs(10)));
+    log_line(&loki, "draining");
+    loki.flush();
+
+    let pushes = server.pushes();
+    assert_eq!(pushes.len(), 2);
+    let stream = &pushes[0].body["streams"][0]["stream"];
+    assert!(stream.get("phase").is_none());
+    assert!(pushes[0].lines()[0].contains("running"));
+    let stream = &pushes[1].body["streams"][0]["stream"];
+    assert_eq!(stream["phase"], "shutdown");
+    assert_eq!(stream["app"], "test");
+    assert!(pushes[1].lines()[0].contains("draining"));
+}
+
+#[test]
+fn summary_counts_retried_lines_as_pending() {
+    let server = MockLokiServer::start().unwrap();
+    let labels = HashMap::from([(String::from("app"), String::from("test"))]);
+    let loki = LokiBuilder::new(server.endpoint(), labels)
+        .failure_policy(FailurePolicy::Retry(5))
+        .shutdown_summary(true)
+        .build();
+
+    // the flush of the shutdown and its retry of the failed batch
+    server.respond_with(503);
+    server.respond_with(503);
+    log_line(&loki, "retried");
+    assert!(loki.shutdown(Duration::from_secs(10)));
+
+    let summary = server
+        .lines()
+        .into_iter()
+        .find(|l| l.contains("log_loki shutting down"))
+        .unwrap();
+    assert!(
+        summary.contains("delivered=0 dropped=0 pending=1"),
+        "{}",
+        summary
+    );
+}
@@ -0,0 +1,43 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+#![cfg(feature = "test-util")]
+
+use log::{Level, Log, Record};
+use log_loki::test_util::MockLokiServer;
+use log_loki::LokiBuilder;
+use std::collections::{HashMap, HashSet};
+
+#[test]
+fn tight_loop_timestamps_are_unique() {
+    let server = MockLokiServer::start().unwrap();
+    let labels = HashMap::from([(String::from("app"), String::from("test"))]);
+    let loki = LokiBuilder::new(server.endpoint(), labels)
+        .max_logs(10_000)
+        .build();
+
+    for i in 0..1000 {
+        loki.log(
+            &Record::builder()
+                .args(format_args!("line {}", i))
+                .level(Level::Info)
+                .build(),
+        );
+    }
+    loki.flush();
+
+    let timestamps: Vec<u128> = server
+        .pushes()
+        .iter()
+        .flat_map(|p| p.body["streams"][0]["values"].as_array().unwrap().clone())
+        .map(|v| v[0].as_str().unwrap().parse().unwrap())
+        .collect();
+    assert_eq!(timestamps.len(), 1000);
+    assert_eq!(timestamps.iter().collect::<HashSet<_>>().len(), 1000);
+    assert!(timestamps.windows(2).all(|w| w[0] < w[1]));
+}